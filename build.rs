@@ -1,5 +1,6 @@
 pub fn main() {
     println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rustc-check-cfg=cfg(nightly, beta, stable, msrv, coverage_nightly)");
     nightly();
     beta();
     stable();
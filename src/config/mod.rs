@@ -20,6 +20,10 @@ use serde::{Deserialize, Serialize};
 pub(crate) struct Config {
     #[getset(get_mut = "pub(crate)")]
     channel: Vec<Channel>,
+
+    /// How to run cargo subcommands that are not built in, for `cargo matrix run`
+    #[serde(default)]
+    subcommand: Vec<Subcommand>,
 }
 
 impl Config {
@@ -126,6 +130,55 @@ impl Config {
         }
     }
 
+    pub(crate) fn timeout(&self, channel: &str) -> Result<Option<u64>> {
+        if let Some(timeout) = self
+            .get_channel(channel)
+            .or_else(|_| self.get_default())?
+            .timeout()
+        {
+            Ok(Some(*timeout))
+        } else {
+            Ok(*self.get_default()?.timeout())
+        }
+    }
+
+    pub(crate) fn retries(&self, channel: &str) -> Result<u32> {
+        if let Some(retries) = self
+            .get_channel(channel)
+            .or_else(|_| self.get_default())?
+            .retries()
+        {
+            Ok(*retries)
+        } else {
+            Ok(self.get_default()?.retries().unwrap_or_default())
+        }
+    }
+
+    pub(crate) fn retry_on(&self, channel: &str) -> Result<Option<String>> {
+        if let Some(retry_on) = self
+            .get_channel(channel)
+            .or_else(|_| self.get_default())?
+            .retry_on()
+        {
+            Ok(Some(retry_on.clone()))
+        } else {
+            Ok(self.get_default()?.retry_on().clone())
+        }
+    }
+
+    /// The subcommand config with the longest name that the given arguments start with
+    pub(crate) fn find_subcommand(&self, args: &[String]) -> Option<&'_ Subcommand> {
+        self.subcommand
+            .iter()
+            .filter(|subcommand| {
+                let words: Vec<&str> = subcommand.name().split_whitespace().collect();
+                !words.is_empty()
+                    && words.len() <= args.len()
+                    && words.iter().zip(args).all(|(word, arg)| word == arg)
+            })
+            .max_by_key(|subcommand| subcommand.name().split_whitespace().count())
+    }
+
     fn get_default(&self) -> Result<&'_ Channel> {
         self.get_channel("default")
     }
@@ -145,6 +198,7 @@ impl Default for Config {
                 name: "default".to_string(),
                 ..Default::default()
             }],
+            subcommand: Vec::new(),
         }
     }
 }
@@ -189,4 +243,35 @@ pub(crate) struct Channel {
     /// Include specific optional dependencies.
     /// This is independent of the `include_all_optional` setting.
     include_optional: Option<FeatureSet>,
+
+    /// Kill a job's cargo process group if it runs longer than this many seconds.
+    timeout: Option<u64>,
+
+    /// Retry a failed job up to this many times. A job that passes on a retry is
+    /// reported as flaky.
+    retries: Option<u32>,
+
+    /// Only retry failures whose output matches this regular expression, i.e.
+    /// `Blocking waiting for file lock`. Every failure is retried if this is not set.
+    retry_on: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Getters, Serialize)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct Subcommand {
+    /// The subcommand words as they follow `cargo matrix run`, i.e. `udeps` or
+    /// `nextest run`.
+    name: String,
+
+    /// The word shown in the job banner, i.e. `Nextest`. Defaults to the capitalized
+    /// first word of the name.
+    verb: Option<String>,
+
+    /// The arguments that select the package and feature set, for tools whose flags
+    /// differ from cargo's. `{package}` and `{features}` are replaced for each job, i.e.
+    /// `["--package", "{package}", "--features={features}"]`. The manifest is only passed
+    /// where the template puts `{manifest}`. Defaults to
+    /// `-p {package} --no-default-features -F {features}`, followed by
+    /// `--manifest-path {manifest}` when one was given.
+    args: Option<Vec<String>>,
 }
@@ -0,0 +1,166 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::FeatureSet;
+use cargo_metadata::Package;
+use std::collections::{BTreeMap, VecDeque};
+
+/// Everything cargo turns on for a set of features, following the `[features]` table of
+/// the package. Each item keeps the path it was first reached by.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Closure {
+    features: BTreeMap<String, Vec<String>>,
+    /// Optional dependencies, by name
+    dependencies: BTreeMap<String, Vec<String>>,
+    /// Features of dependencies, i.e. `serde/std` or the weak `serde?/std`
+    dependency_features: BTreeMap<String, Vec<String>>,
+}
+
+impl Closure {
+    pub(crate) fn new(package: &Package, set: &FeatureSet) -> Self {
+        let mut closure = Self::default();
+        let mut queue: VecDeque<(String, Vec<String>)> = set
+            .iter()
+            .map(|feature| (feature.0.clone(), vec![feature.0.clone()]))
+            .collect();
+
+        while let Some((feature, path)) = queue.pop_front() {
+            if closure.features.contains_key(&feature) {
+                continue;
+            }
+            let enables = package.features.get(&feature).cloned().unwrap_or_default();
+            let _ = closure.features.insert(feature, path.clone());
+
+            for value in enables {
+                let via = |item: &str| {
+                    let mut via = path.clone();
+                    via.push(item.to_string());
+                    via
+                };
+                if let Some(dependency) = value.strip_prefix("dep:") {
+                    let _ = closure
+                        .dependencies
+                        .entry(dependency.to_string())
+                        .or_insert_with(|| via(&value));
+                } else if let Some((dependency, _)) = value.split_once('/') {
+                    let _ = closure
+                        .dependency_features
+                        .entry(value.clone())
+                        .or_insert_with(|| via(&value));
+                    // A weak dependency feature does not enable the dependency
+                    if !dependency.ends_with('?') {
+                        if package.features.contains_key(dependency) {
+                            queue.push_back((dependency.to_string(), via(dependency)));
+                        } else {
+                            let _ = closure
+                                .dependencies
+                                .entry(dependency.to_string())
+                                .or_insert_with(|| via(dependency));
+                        }
+                    }
+                } else {
+                    queue.push_back((value.clone(), via(&value)));
+                }
+            }
+        }
+        closure
+    }
+
+    pub(crate) fn features(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.features
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    pub(crate) fn dependencies(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.dependencies
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    pub(crate) fn dependency_features(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.dependency_features
+            .iter()
+            .map(|(k, v)| (k.as_str(), v.as_slice()))
+    }
+
+    /// How a feature was reached, if it was
+    pub(crate) fn path(&self, feature: &str) -> Option<&[String]> {
+        self.features.get(feature).map(Vec::as_slice)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Closure;
+    use crate::feature::{fixture, FeatureSet};
+
+    fn closure(features: &[&str]) -> Closure {
+        let mut package = fixture("deny");
+        // The fixture has no dependency features, weak or not
+        let _ = package.features.insert(
+            "feat-e".to_string(),
+            vec!["temp-env/std".to_string(), "rand?/std".to_string()],
+        );
+        let set: FeatureSet = features.iter().copied().map(Into::into).collect();
+        Closure::new(&package, &set)
+    }
+
+    fn items<'a>(items: impl Iterator<Item = (&'a str, &'a [String])>) -> Vec<(&'a str, String)> {
+        items
+            .map(|(item, path)| (item, path.join(" -> ")))
+            .collect()
+    }
+
+    #[test]
+    fn follows_features_to_dependencies() {
+        let closure = closure(&["feat-a"]);
+        assert_eq!(
+            items(closure.features()),
+            [
+                ("feat-a", "feat-a".to_string()),
+                ("feat-b", "feat-a -> feat-b".to_string()),
+                ("rand", "feat-a -> feat-b -> rand".to_string()),
+            ]
+        );
+        assert_eq!(
+            items(closure.dependencies()),
+            [("rand", "feat-a -> feat-b -> rand -> dep:rand".to_string())]
+        );
+        assert_eq!(closure.dependency_features().count(), 0);
+        assert_eq!(
+            closure.path("feat-b"),
+            Some(&["feat-a".to_string(), "feat-b".to_string()][..])
+        );
+        assert_eq!(closure.path("feat-c"), None);
+    }
+
+    #[test]
+    fn only_strong_dependency_features_enable_the_dependency() {
+        let closure = closure(&["feat-e"]);
+        assert_eq!(
+            items(closure.dependency_features()),
+            [
+                ("rand?/std", "feat-e -> rand?/std".to_string()),
+                ("temp-env/std", "feat-e -> temp-env/std".to_string()),
+            ]
+        );
+        // `temp-env` is also the implicit feature of the dependency, which enables it
+        assert_eq!(
+            items(closure.features()),
+            [
+                ("feat-e", "feat-e".to_string()),
+                ("temp-env", "feat-e -> temp-env".to_string()),
+            ]
+        );
+        assert_eq!(
+            items(closure.dependencies()),
+            [("temp-env", "feat-e -> temp-env -> dep:temp-env".to_string())]
+        );
+    }
+}
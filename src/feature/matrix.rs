@@ -11,10 +11,12 @@ use crate::config::Config;
 use anyhow::Result;
 use cargo_metadata::Package;
 use derive_more::{AsMut, AsRef, Deref, DerefMut};
+use getset::CopyGetters;
 use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 use std::{
     collections::{BTreeSet, HashSet},
+    fmt::{Display, Formatter},
     ops::Deref as OpsDeref,
 };
 
@@ -22,13 +24,84 @@ use std::{
 #[serde(transparent)]
 pub(crate) struct Matrix(BTreeSet<FeatureSet>);
 
+/// How many feature sets each rule took out of the matrix.
+///
+/// These count sets, not features. A feature left out of the seed never makes it into a
+/// set, so it is counted as the sets the matrix would have had with it: each one halves
+/// what is left. Those rules are charged in the order implicit, deny, hidden, which makes
+/// the counts depend on that order. A seed of two features with one implicit and one
+/// denied feature left out counts 8 implicit and 4 deny, where charging deny first would
+/// count 8 deny and 4 implicit.
+#[derive(Clone, Copy, CopyGetters, Debug, Default, Serialize)]
+#[getset(get_copy = "pub(crate)")]
+pub(crate) struct Pruning {
+    /// Features of the shape `foo = ["dep:foo"]`
+    implicit: u64,
+    /// Features starting with `__`
+    hidden: u64,
+    deny: u64,
+    skip: u64,
+    /// Sets that became the same once the always included features were added
+    duplicate: u64,
+}
+
+/// A rule that keeps a feature set out of the matrix
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) enum Removal {
+    /// The feature is not in the `[features]` table of the package
+    Unknown(Feature),
+    /// `default` is never part of a set, sets are built on `--no-default-features`
+    Default,
+    Implicit(Feature),
+    Hidden(Feature),
+    Denied(Feature),
+    /// The channel seeds the matrix with features that do not include this one
+    NotInSeed(Feature),
+    /// Every set of the matrix has the always included features
+    MissingInclude(Feature),
+    /// The set is one of the skip entries
+    Skipped(FeatureSet),
+}
+
+impl Display for Removal {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Removal::Unknown(feature) => write!(f, "`{feature}` is not a feature of the package"),
+            Removal::Default => write!(f, "`default` is never part of a feature set"),
+            Removal::Implicit(feature) => write!(
+                f,
+                "`{feature}` is the implicit feature of an optional dependency, add it with include_optional"
+            ),
+            Removal::Hidden(feature) => {
+                write!(f, "`{feature}` is hidden, add it with include_hidden")
+            }
+            Removal::Denied(feature) => write!(f, "denied by always_deny entry `{feature}`"),
+            Removal::NotInSeed(feature) => write!(f, "`{feature}` is not in the seed"),
+            Removal::MissingInclude(feature) => {
+                write!(f, "missing always_include entry `{feature}`")
+            }
+            Removal::Skipped(set) => write!(f, "matched skip entry `[{set}]`"),
+        }
+    }
+}
+
 impl Matrix {
     pub(crate) fn new(package: &Package, config: &Config, channel: &str) -> Result<Self> {
+        Ok(Self::pruned(package, config, channel)?.0)
+    }
+
+    /// The matrix, along with how many feature sets each rule took out of it
+    pub(crate) fn pruned(
+        package: &Package,
+        config: &Config,
+        channel: &str,
+    ) -> Result<(Self, Pruning)> {
         let deny = config.always_deny(channel)?;
         let skip = config.skip(channel)?;
         let include = config.always_include(channel)?;
 
-        Ok(Self::extract_seed(package, config, channel)?
+        let (seed, mut pruning) = Self::extract_seed(package, config, channel)?;
+        let sets: Vec<FeatureSet> = seed
             .into_iter()
             .powerset()
             .map(FeatureSet::from_iter)
@@ -37,17 +110,95 @@ impl Matrix {
                 set.extend(include.clone());
                 set
             })
-            // Re-check deny in case a custom seed was used
+            .collect();
+        let candidates = sets.len();
+        // Re-check deny in case a custom seed was used
+        let sets: Vec<FeatureSet> = sets
+            .into_iter()
             .filter(|set| set.is_disjoint(&deny))
-            // Skip any configured matricies
+            .collect();
+        pruning.deny += (candidates - sets.len()) as u64;
+        let allowed = sets.len();
+        // Skip any configured matricies
+        let sets: Vec<FeatureSet> = sets
+            .into_iter()
             .filter(|set| !skip.iter().any(|skip| skip == set))
-            .collect())
+            .collect();
+        pruning.skip = (allowed - sets.len()) as u64;
+        let unskipped = sets.len();
+        let matrix: Self = sets.into_iter().collect();
+        pruning.duplicate = (unskipped - matrix.len()) as u64;
+        Ok((matrix, pruning))
+    }
+
+    /// Every rule that keeps the feature set out of the matrix, in the order they are
+    /// applied. There are none when the set is in the matrix.
+    pub(crate) fn explain(
+        package: &Package,
+        config: &Config,
+        channel: &str,
+        set: &FeatureSet,
+    ) -> Result<Vec<Removal>> {
+        if Self::new(package, config, channel)?.contains(set) {
+            return Ok(Vec::new());
+        }
+        let deny = config.always_deny(channel)?;
+        let skip = config.skip(channel)?;
+        let include = config.always_include(channel)?;
+        let (seed, _) = Self::extract_seed(package, config, channel)?;
+        let custom_seed = config.seed(channel)?.is_some();
+        let implicit_features = Self::find_implicits(package);
+        let optional: HashSet<&str> = package
+            .dependencies
+            .iter()
+            .filter(|dependency| dependency.optional)
+            .map(|dependency| dependency.rename.as_deref().unwrap_or(&dependency.name))
+            .collect();
+
+        let mut removals = Vec::new();
+        for feature in set.iter().filter(|feature| !include.contains(*feature)) {
+            let removal = if deny.contains(feature) {
+                Removal::Denied(feature.clone())
+            } else if seed.contains(feature) {
+                continue;
+            } else if **feature == "default" {
+                Removal::Default
+            } else if !package.features.contains_key(&feature.0)
+                && !optional.contains(feature.as_str())
+            {
+                Removal::Unknown(feature.clone())
+            } else if custom_seed {
+                Removal::NotInSeed(feature.clone())
+            } else if implicit_features.contains(feature) {
+                Removal::Implicit(feature.clone())
+            } else if feature.starts_with("__") {
+                Removal::Hidden(feature.clone())
+            } else {
+                Removal::NotInSeed(feature.clone())
+            };
+            removals.push(removal);
+        }
+        removals.extend(
+            include
+                .iter()
+                .filter(|feature| !set.contains(*feature))
+                .cloned()
+                .map(Removal::MissingInclude),
+        );
+        if skip.contains(set) {
+            removals.push(Removal::Skipped(set.clone()));
+        }
+        Ok(removals)
     }
 
     /// Reads the package + config and outputs the set of features that should be used to seed the matrix.
-    fn extract_seed(package: &Package, config: &Config, channel: &str) -> Result<FeatureSet> {
+    fn extract_seed(
+        package: &Package,
+        config: &Config,
+        channel: &str,
+    ) -> Result<(FeatureSet, Pruning)> {
         Ok(if let Some(seed) = config.seed(channel)? {
-            seed.clone()
+            (seed.clone(), Pruning::default())
         } else {
             let implicit_features = Self::find_implicits(package);
             let deny = config.always_deny(channel)?;
@@ -91,11 +242,47 @@ impl Matrix {
             // Add in the specific optional dependencies requested
             set.extend(config.include_optional(channel)?);
 
-            set
+            // The features each rule left out, in the order the rules apply
+            let include_hidden = config.include_hidden(channel).unwrap_or_default();
+            let left_out = |rule: &dyn Fn(&Feature) -> bool| {
+                package
+                    .features
+                    .keys()
+                    .map(Feature::from)
+                    .filter(|feature| **feature != "default" && !include.contains(feature))
+                    .filter(|feature| !set.contains(feature) && rule(feature))
+                    .count()
+            };
+            let implicit = left_out(&|feature| implicit_features.contains(feature));
+            let denied =
+                left_out(&|feature| !implicit_features.contains(feature) && deny.contains(feature));
+            let hidden = left_out(&|feature| {
+                !implicit_features.contains(feature)
+                    && !deny.contains(feature)
+                    && !include_hidden
+                    && feature.starts_with("__")
+            });
+
+            // Every feature left out halves the matrix
+            let mut size = 1_u64
+                .checked_shl((set.len() + implicit + denied + hidden) as u32)
+                .unwrap_or(u64::MAX);
+            let mut halve = |features: usize| {
+                let removed = size - size.checked_shr(features as u32).unwrap_or_default();
+                size -= removed;
+                removed
+            };
+            let pruning = Pruning {
+                implicit: halve(implicit),
+                deny: halve(denied),
+                hidden: halve(hidden),
+                ..Pruning::default()
+            };
+            (set, pruning)
         })
     }
 
-    fn find_implicits(package: &Package) -> HashSet<Feature> {
+    pub(crate) fn find_implicits(package: &Package) -> HashSet<Feature> {
         let mut implicit_features = HashSet::<Feature>::new();
         let mut optional_dep: HashSet<Feature> = HashSet::new();
 
@@ -138,3 +325,94 @@ impl IntoIterator for Matrix {
         self.0.into_iter()
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{Matrix, Removal};
+    use crate::{
+        feature::{fixture, FeatureSet},
+        runtime::generate_config,
+    };
+
+    fn set(features: &[&str]) -> FeatureSet {
+        features.iter().copied().map(Into::into).collect()
+    }
+
+    fn explain(channel: &str, features: &[&str]) -> Vec<Removal> {
+        let package = fixture("deny");
+        let (_, config) = generate_config(&package).unwrap();
+        Matrix::explain(&package, &config, channel, &set(features)).unwrap()
+    }
+
+    #[test]
+    fn counts_the_sets_each_rule_pruned() {
+        let package = fixture("deny");
+        let (_, config) = generate_config(&package).unwrap();
+        let (matrix, pruning) = Matrix::pruned(&package, &config, "default").unwrap();
+        // Seeded with feat-a, feat-b, feat-c and temp-env, leaving out the implicit rand,
+        // the denied feat-d and unstable, and the hidden __vergen_test: 2^8 sets halved
+        // once, then twice, then once more
+        assert_eq!(
+            (
+                pruning.implicit(),
+                pruning.hidden(),
+                pruning.deny(),
+                pruning.skip(),
+                pruning.duplicate()
+            ),
+            (128, 16, 96, 1, 0)
+        );
+        assert_eq!(matrix.len(), 15);
+    }
+
+    #[test]
+    fn explains_nothing_for_sets_in_the_matrix() {
+        assert!(explain("default", &["feat-a", "temp-env"]).is_empty());
+        assert!(explain("nightly", &["__vergen_test", "unstable"]).is_empty());
+    }
+
+    #[test]
+    fn explains_each_rule() {
+        assert_eq!(
+            explain("default", &["feat-d"]),
+            [Removal::Denied("feat-d".into())]
+        );
+        assert_eq!(
+            explain("default", &["feat-c", "temp-env"]),
+            [Removal::Skipped(set(&["feat-c", "temp-env"]))]
+        );
+        assert_eq!(
+            explain("nightly", &["feat-a"]),
+            [Removal::MissingInclude("__vergen_test".into())]
+        );
+        assert_eq!(
+            explain("default", &["rand"]),
+            [Removal::Implicit("rand".into())]
+        );
+        assert_eq!(
+            explain("default", &["__vergen_test"]),
+            [Removal::Hidden("__vergen_test".into())]
+        );
+        assert_eq!(
+            explain("default", &["nope"]),
+            [Removal::Unknown("nope".into())]
+        );
+        assert_eq!(explain("default", &["default"]), [Removal::Default]);
+        assert_eq!(
+            explain("seeded", &["feat-b"]),
+            [Removal::NotInSeed("feat-b".into())]
+        );
+    }
+
+    #[test]
+    fn explains_every_rule_in_order() {
+        assert_eq!(
+            explain("nightly", &["feat-d", "rand", "feat-a"]),
+            [
+                Removal::Denied("feat-d".into()),
+                Removal::Implicit("rand".into()),
+                Removal::MissingInclude("__vergen_test".into()),
+            ]
+        );
+    }
+}
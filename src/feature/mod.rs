@@ -6,6 +6,7 @@
 // option. All files in the project carrying such notice may not be copied,
 // modified, or distributed except according to those terms.
 
+mod closure;
 mod matrix;
 mod set;
 
@@ -13,7 +14,9 @@ use derive_more::{AsMut, AsRef, Deref, DerefMut};
 use serde::{Deserialize, Serialize};
 use std::fmt::{Display, Formatter};
 
+pub(crate) use self::closure::Closure;
 pub(crate) use self::matrix::Matrix as FeatureMatrix;
+pub(crate) use self::matrix::Pruning;
 pub(crate) use self::set::Set as FeatureSet;
 
 #[derive(
@@ -36,6 +39,21 @@ pub(crate) use self::set::Set as FeatureSet;
 #[as_mut(forward)]
 pub(crate) struct Feature(pub(crate) String);
 
+/// The package of a fixture under `tests/testdata`
+#[cfg(test)]
+pub(crate) fn fixture(name: &str) -> cargo_metadata::Package {
+    let manifest = std::path::Path::new(env!("CARGO_MANIFEST_DIR"))
+        .join("tests/testdata")
+        .join(name)
+        .join("Cargo.toml");
+    let metadata = cargo_metadata::MetadataCommand::new()
+        .manifest_path(manifest)
+        .no_deps()
+        .exec()
+        .unwrap();
+    metadata.packages.into_iter().next().unwrap()
+}
+
 impl Display for Feature {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.0, f)
@@ -0,0 +1,404 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::FeatureSet,
+    runtime::execute::{Job, JobReport, TaskResult},
+};
+use anyhow::{anyhow, Result};
+use cargo_metadata::{Metadata, PackageId};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    env::{current_dir, var_os, vars},
+    fmt::Write as _,
+    fs,
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+/// Bump when the key material changes, so old entries are never matched
+const KEY_VERSION: &str = "cargo-matrix cache v2";
+
+/// Environment variables that cannot change the outcome of a job
+const IGNORED_ENV: &[&str] = &[
+    "CARGO_MAKEFLAGS",
+    "CARGO_TARGET_DIR",
+    "CARGO_TERM_COLOR",
+    "CARGO_TERM_PROGRESS_WHEN",
+    "CARGO_TERM_PROGRESS_WIDTH",
+    "CARGO_TERM_QUIET",
+    "CARGO_TERM_VERBOSE",
+];
+
+/// A cached pass, stored as `<key>.json`
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    package: String,
+    features: FeatureSet,
+    command: String,
+    created: u64,
+}
+
+/// Remembers which jobs passed, keyed by a hash of everything that can change their
+/// outcome: the sources of the package and its local dependencies, `Cargo.lock`, the
+/// toolchain, the cargo config, the environment and the command line itself.
+#[derive(Debug)]
+pub(crate) struct Cache {
+    dir: PathBuf,
+    /// The key material shared by every job in the workspace
+    base: String,
+    /// The combined source hash of each package and its local dependencies, by name
+    sources: HashMap<String, String>,
+}
+
+impl Cache {
+    /// Open the cache in `dir`, hashing the sources of the given packages up front.
+    /// `outputs` are the files and directories the run writes to, which are never part
+    /// of the sources even when they are inside a package.
+    pub(crate) fn open<'a>(
+        dir: PathBuf,
+        metadata: &Metadata,
+        packages: impl IntoIterator<Item = &'a str>,
+        outputs: &[PathBuf],
+    ) -> Result<Self> {
+        let root = metadata.workspace_root.as_std_path();
+        let cwd = current_dir()?;
+        let outputs: Vec<PathBuf> = outputs.iter().map(|output| cwd.join(output)).collect();
+        let mut base = format!("{KEY_VERSION}\n");
+        writeln!(base, "lock {}", hash_file(&root.join("Cargo.lock"))?)?;
+        for config in [".cargo/config", ".cargo/config.toml"] {
+            writeln!(base, "config {config} {}", hash_file(&root.join(config))?)?;
+        }
+        writeln!(base, "toolchain {}", toolchain(root)?)?;
+        let env: BTreeMap<String, String> = vars()
+            .filter(|(key, _)| key.starts_with("CARGO") || key.starts_with("RUST"))
+            .filter(|(key, _)| !IGNORED_ENV.contains(&key.as_str()))
+            .collect();
+        for (key, value) in env {
+            writeln!(base, "env {key}={value}")?;
+        }
+
+        let mut hashed = HashMap::new();
+        let mut sources = HashMap::new();
+        for name in packages {
+            if sources.contains_key(name) {
+                continue;
+            }
+            let package = metadata
+                .workspace_packages()
+                .into_iter()
+                .find(|package| package.name == name)
+                .ok_or_else(|| anyhow!("package '{name}' is not in the workspace"))?;
+            let mut source = String::new();
+            for id in local_closure(metadata, &package.id) {
+                if !hashed.contains_key(&id) {
+                    let dir = metadata[&id]
+                        .manifest_path
+                        .parent()
+                        .map(|dir| dir.as_std_path().to_path_buf())
+                        .unwrap_or_default();
+                    let _ = hashed.insert(id.clone(), hash_dir(&dir, &outputs)?);
+                }
+                writeln!(source, "source {} {}", metadata[&id].name, hashed[&id])?;
+            }
+            let _ = sources.insert(name.to_string(), source);
+        }
+
+        Ok(Self { dir, base, sources })
+    }
+
+    /// Did this exact job pass before
+    pub(crate) fn passed(&self, job: &Job) -> bool {
+        self.entry(job).is_some_and(|path| path.exists())
+    }
+
+    /// Remember a passing job, anything else is left alone
+    pub(crate) fn store(&self, report: &JobReport) -> Result<()> {
+        if !matches!(report.result(), TaskResult::Success | TaskResult::Flaky) {
+            return Ok(());
+        }
+        let job = report.job();
+        let Some(path) = self.entry(job) else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let entry = Entry {
+            package: job.package().clone(),
+            features: job.feature_set().clone(),
+            command: job.command_line(),
+            created: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        };
+        fs::write(path, serde_json::to_vec_pretty(&entry)?)?;
+        Ok(())
+    }
+
+    fn entry(&self, job: &Job) -> Option<PathBuf> {
+        let source = self.sources.get(job.package())?;
+        let mut hasher = Sha256::new();
+        hasher.update(&self.base);
+        hasher.update(source);
+        hasher.update(format!("command {}\n", job.command_line()));
+        let key = hex(&hasher.finalize());
+        Some(self.dir.join(&key[..2]).join(format!("{key}.json")))
+    }
+}
+
+/// Remove cached results, or only those older than `older_than`. Returns how many were
+/// removed.
+pub(crate) fn prune(dir: &Path, older_than: Option<Duration>) -> Result<usize> {
+    if !dir.exists() {
+        return Ok(0);
+    }
+    let now = SystemTime::now();
+    let mut removed = 0;
+    for shard in fs::read_dir(dir)? {
+        let shard = shard?.path();
+        if !shard.is_dir() {
+            continue;
+        }
+        for entry in fs::read_dir(&shard)? {
+            let entry = entry?;
+            let age = now
+                .duration_since(entry.metadata()?.modified()?)
+                .unwrap_or_default();
+            let expired = match older_than {
+                Some(older_than) => age >= older_than,
+                None => true,
+            };
+            if expired {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        if fs::read_dir(&shard)?.next().is_none() {
+            fs::remove_dir(&shard)?;
+        }
+    }
+    Ok(removed)
+}
+
+/// The package and every package it depends on that lives on disk rather than in a
+/// registry or git checkout
+fn local_closure(metadata: &Metadata, root: &PackageId) -> BTreeSet<PackageId> {
+    let mut closure = BTreeSet::new();
+    let mut queue = vec![root.clone()];
+    while let Some(id) = queue.pop() {
+        if metadata[&id].source.is_some() || !closure.insert(id.clone()) {
+            continue;
+        }
+        if let Some(node) = metadata
+            .resolve
+            .as_ref()
+            .and_then(|resolve| resolve.nodes.iter().find(|node| node.id == id))
+        {
+            queue.extend(node.deps.iter().map(|dep| dep.pkg.clone()));
+        }
+    }
+    closure
+}
+
+/// The verbose version of the rustc that cargo would use in the workspace, which
+/// respects `rust-toolchain.toml` through rustup
+fn toolchain(root: &Path) -> Result<String> {
+    let rustc = var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let output = Command::new(rustc).arg("-vV").current_dir(root).output()?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "unable to determine the rustc version for the cache key"
+        ));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .trim()
+        .replace('\n', ";"))
+}
+
+fn hash_file(path: &Path) -> Result<String> {
+    if !path.exists() {
+        return Ok("none".to_string());
+    }
+    Ok(hex(&Sha256::digest(fs::read(path)?)))
+}
+
+/// Hash the files a package is built from by relative path and contents. In a git
+/// repository these are the files git tracks along with the untracked ones it does not
+/// ignore, as for `cargo package`, since a new module is built before it is added.
+/// Otherwise it is every file below `dir` outside of build output and hidden
+/// directories. Files of nested packages and the `outputs` of the run are left out
+/// either way.
+fn hash_dir(dir: &Path, outputs: &[PathBuf]) -> Result<String> {
+    let mut files = match git_files(dir) {
+        Some(files) => files,
+        None => walk(dir)?,
+    };
+    files.retain(|file| {
+        !outputs.iter().any(|output| file.starts_with(output)) && !in_nested_package(dir, file)
+    });
+    files.sort();
+
+    let mut hasher = Sha256::new();
+    for file in files {
+        let relative = file.strip_prefix(dir).unwrap_or(&file);
+        hasher.update(relative.to_string_lossy().as_bytes());
+        hasher.update([0]);
+        // A tracked file may have been deleted from the working tree
+        hasher.update(hash_file(&file)?);
+    }
+    Ok(hex(&hasher.finalize()))
+}
+
+/// The files below `dir` git tracks or does not ignore, or `None` outside a repository
+/// or if git ignores the manifest
+fn git_files(dir: &Path) -> Option<Vec<PathBuf>> {
+    let output = Command::new("git")
+        .args([
+            "ls-files",
+            "-z",
+            "--cached",
+            "--others",
+            "--exclude-standard",
+        ])
+        .current_dir(dir)
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let files: Vec<PathBuf> = output
+        .stdout
+        .split(|byte| *byte == 0)
+        .filter(|path| !path.is_empty())
+        .map(|path| dir.join(String::from_utf8_lossy(path).as_ref()))
+        .collect();
+    files.contains(&dir.join("Cargo.toml")).then_some(files)
+}
+
+/// Every file below `dir`, skipping build output and hidden directories such as `.git`
+fn walk(dir: &Path) -> Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut pending = vec![dir.to_path_buf()];
+    while let Some(current) = pending.pop() {
+        for entry in fs::read_dir(&current)? {
+            let entry = entry?;
+            let path = entry.path();
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if entry.file_type()?.is_dir() {
+                if name != "target" && !name.starts_with('.') {
+                    pending.push(path);
+                }
+            } else {
+                files.push(path);
+            }
+        }
+    }
+    Ok(files)
+}
+
+/// Is the file part of another package below `dir`, such as a workspace member under
+/// the root package
+fn in_nested_package(dir: &Path, file: &Path) -> bool {
+    file.ancestors()
+        .skip(1)
+        .take_while(|ancestor| *ancestor != dir && ancestor.starts_with(dir))
+        .any(|ancestor| ancestor.join("Cargo.toml").is_file())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::hash_dir;
+    use std::{
+        env, fs,
+        path::{Path, PathBuf},
+        process::Command,
+    };
+
+    /// A package with a library in a fresh directory under the system temp directory
+    fn package(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("cargo-matrix-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("src")).unwrap();
+        fs::write(dir.join("Cargo.toml"), "[package]\nname = \"cached\"\n").unwrap();
+        fs::write(dir.join("src/lib.rs"), "pub fn f() {}\n").unwrap();
+        dir
+    }
+
+    fn write(dir: &Path, file: &str, contents: &str) {
+        let path = dir.join(file);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn outputs_of_the_run_do_not_change_the_key() {
+        let dir = package("outputs");
+        let outputs = [
+            dir.join("logs"),
+            dir.join("state.jsonl"),
+            dir.join("report.json"),
+        ];
+        let key = hash_dir(&dir, &outputs).unwrap();
+
+        write(&dir, "logs/0001-cached.log", "Compiling cached");
+        write(&dir, "state.jsonl", "{}");
+        write(&dir, "report.json", "{}");
+        write(&dir, "target/debug/cached", "");
+        write(&dir, "member/Cargo.toml", "[package]\nname = \"member\"\n");
+        write(&dir, "member/src/lib.rs", "");
+        assert_eq!(hash_dir(&dir, &outputs).unwrap(), key);
+
+        write(&dir, "src/lib.rs", "pub fn g() {}\n");
+        assert_ne!(hash_dir(&dir, &outputs).unwrap(), key);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn ignored_files_do_not_change_the_key() {
+        let dir = package("tracked");
+        let git = |args: &[&str]| {
+            let status = Command::new("git")
+                .args(args)
+                .current_dir(&dir)
+                .output()
+                .unwrap()
+                .status;
+            assert!(status.success(), "git {args:?}");
+        };
+        git(&["init", "--quiet"]);
+        write(&dir, ".gitignore", "/results\n");
+        git(&["add", "Cargo.toml", "src/lib.rs"]);
+        let key = hash_dir(&dir, &[]).unwrap();
+
+        write(&dir, "results/report.html", "<html>");
+        assert_eq!(hash_dir(&dir, &[]).unwrap(), key);
+
+        // A module that is not added yet is still built
+        write(&dir, "src/foo.rs", "pub fn h() {}\n");
+        let untracked = hash_dir(&dir, &[]).unwrap();
+        assert_ne!(untracked, key);
+
+        // Tracked files count with what is in the working tree, staged or not
+        write(&dir, "src/lib.rs", "pub fn g() {}\n");
+        assert_ne!(hash_dir(&dir, &[]).unwrap(), untracked);
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
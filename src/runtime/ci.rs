@@ -0,0 +1,186 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::{
+    execute::{Job, JobReport},
+    report::Report,
+};
+use anyhow::Result;
+use std::{
+    env::{var, var_os},
+    fs::OpenOptions,
+    io::{BufWriter, Write},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+/// The CI service we are running under, if it has its own way of presenting logs
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Ci {
+    GitHub,
+    GitLab,
+}
+
+impl Ci {
+    pub(crate) fn detect() -> Option<Self> {
+        if var("GITHUB_ACTIONS").is_ok_and(|value| value == "true") {
+            Some(Ci::GitHub)
+        } else if var("GITLAB_CI").is_ok_and(|value| value == "true") {
+            Some(Ci::GitLab)
+        } else {
+            None
+        }
+    }
+
+    /// Start a collapsible group holding the output of the job at `index` in the plan
+    pub(crate) fn start_group(self, out: &mut dyn Write, index: usize, job: &Job) -> Result<()> {
+        let title = title(job);
+        match self {
+            Ci::GitHub => writeln!(out, "::group::{title}")?,
+            Ci::GitLab => writeln!(
+                out,
+                "\x1b[0Ksection_start:{}:{}[collapsed=true]\r\x1b[0K{title}",
+                timestamp(),
+                section(index, job)
+            )?,
+        }
+        Ok(())
+    }
+
+    /// End the group of the job, and annotate it if it failed
+    pub(crate) fn end_group(
+        self,
+        out: &mut dyn Write,
+        index: usize,
+        report: &JobReport,
+    ) -> Result<()> {
+        match self {
+            Ci::GitHub => {
+                writeln!(out, "::endgroup::")?;
+                if report.result().is_failure() {
+                    writeln!(
+                        out,
+                        "::error title={}::{}",
+                        escape_property(&format!(
+                            "{} {}",
+                            title(report.job()),
+                            report.result().label()
+                        )),
+                        escape_data(&format!("Reproduce with: {}", report.job().command_line()))
+                    )?;
+                }
+            }
+            Ci::GitLab => writeln!(
+                out,
+                "\x1b[0Ksection_end:{}:{}\r\x1b[0K",
+                timestamp(),
+                section(index, report.job())
+            )?,
+        }
+        Ok(())
+    }
+
+    /// Add a table of the results to the summary page of the workflow run
+    pub(crate) fn write_summary(self, reports: &[JobReport]) -> Result<()> {
+        if let (Ci::GitHub, Some(path)) = (self, var_os("GITHUB_STEP_SUMMARY")) {
+            let file = OpenOptions::new().create(true).append(true).open(path)?;
+            let mut out = BufWriter::new(file);
+            Report::new(reports).write_markdown(&mut out)?;
+            writeln!(out)?;
+            out.flush()?;
+        }
+        Ok(())
+    }
+}
+
+fn title(job: &Job) -> String {
+    format!(
+        "{} [{}] ({})",
+        job.package(),
+        job.feature_set(),
+        job.channel()
+    )
+}
+
+/// The id of a GitLab section. The compile phase and the subsets tried when minimizing
+/// run under the index of their planned job, so the job id tells them apart.
+fn section(index: usize, job: &Job) -> String {
+    format!("cargo_matrix_{index}_{}", job.id())
+}
+
+fn timestamp() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or_default()
+}
+
+/// Workflow commands end at a newline, so those and `%` are percent encoded
+fn escape_data(text: &str) -> String {
+    text.replace('%', "%25")
+        .replace('\r', "%0D")
+        .replace('\n', "%0A")
+}
+
+/// Properties also end at `,` and `::`
+fn escape_property(text: &str) -> String {
+    escape_data(text).replace(':', "%3A").replace(',', "%2C")
+}
+
+#[cfg(test)]
+mod test {
+    use super::Ci;
+    use crate::runtime::execute::{Job, JobReport, TaskResult};
+
+    fn group(ci: Ci, result: TaskResult) -> String {
+        let job = Job::check("foo", &["a", "b"]);
+        let mut out = Vec::new();
+        ci.start_group(&mut out, 3, &job).unwrap();
+        ci.end_group(&mut out, 3, &JobReport::ran(&job, result, None))
+            .unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn github_groups_and_annotates_failures() {
+        assert_eq!(
+            group(Ci::GitHub, TaskResult::Success),
+            "::group::foo [a,b] (default)\n::endgroup::\n"
+        );
+        let failed = group(Ci::GitHub, TaskResult::Fail(101));
+        let lines: Vec<&str> = failed.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[1], "::endgroup::");
+        // The `,` and `:` of the title would end the property
+        assert!(
+            lines[2].starts_with("::error title=foo [a%2Cb] (default) FAILED::Reproduce with: "),
+            "{failed}"
+        );
+        assert!(lines[2].ends_with("check -p foo --no-default-features -F a,b"));
+    }
+
+    #[test]
+    fn gitlab_opens_and_closes_the_same_section() {
+        let job = Job::check("foo", &["a", "b"]);
+        let out = group(Ci::GitLab, TaskResult::Fail(101));
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines.len(), 2);
+        let section = format!("cargo_matrix_3_{}", job.id());
+        assert!(
+            lines[0].starts_with("\x1b[0Ksection_start:")
+                && lines[0].ends_with(&format!(
+                    ":{section}[collapsed=true]\r\x1b[0Kfoo [a,b] (default)"
+                )),
+            "{out:?}"
+        );
+        assert!(
+            lines[1].starts_with("\x1b[0Ksection_end:")
+                && lines[1].ends_with(&format!(":{section}\r\x1b[0K")),
+            "{out:?}"
+        );
+    }
+}
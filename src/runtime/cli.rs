@@ -6,7 +6,8 @@
 // option. All files in the project carrying such notice may not be copied,
 // modified, or distributed except according to those terms.
 
-use clap::{crate_authors, crate_description, crate_version, Args, Parser, Subcommand};
+use crate::runtime::{report::Spec as ReportSpec, shard::Shard};
+use clap::{crate_authors, crate_description, crate_version, Args, Parser, Subcommand, ValueEnum};
 use getset::Getters;
 use std::path::PathBuf;
 
@@ -34,12 +35,68 @@ pub(crate) struct MatrixArgs {
     #[clap(long)]
     dry_run: bool,
 
+    /// Run up to 'n' feature sets concurrently, each worker using its own target directory
+    #[arg(long, short, default_value_t = 1)]
+    jobs: usize,
+
+    /// Run every job even if some fail, then print a summary of the results
+    #[arg(long)]
+    keep_going: bool,
+
+    /// Stop scheduling new jobs after 'n' failures when keeping going
+    #[arg(long, requires = "keep_going")]
+    max_failures: Option<usize>,
+
+    /// Shrink each failing feature set to a smallest one the matrix allows that still fails
+    /// with the same error, by running the same command on subsets of it
+    #[arg(long)]
+    minimize: bool,
+
+    /// Write the output of each job to its own file in this directory, along with an index.
+    /// The logs and index of an earlier run are removed first.
+    #[arg(long)]
+    log_dir: Option<PathBuf>,
+
+    /// Kill a job's cargo process group after this many seconds, overriding the channel config
+    #[arg(long, value_name = "SECONDS")]
+    timeout: Option<u64>,
+
+    /// Retry a failed job up to 'n' times, overriding the channel config
+    #[arg(long)]
+    retries: Option<u32>,
+
+    /// Only retry failures whose output matches this regex, overriding the channel config
+    #[arg(long, value_name = "REGEX")]
+    retry_on: Option<String>,
+
+    /// Record the result of every completed job in this file as the run goes
+    #[arg(long, value_name = "FILE")]
+    state: Option<PathBuf>,
+
+    /// Skip the jobs that already passed according to the state file
+    #[arg(long, requires = "state")]
+    resume: bool,
+
+    /// Skip jobs whose sources, toolchain, environment and command line match an earlier
+    /// passing run, keeping results under `<target>/cargo-matrix/cache`
+    #[arg(long)]
+    cache: bool,
+
+    /// Write the results in this format to this file, i.e. `json=results.json`. Can be
+    /// repeated, the formats are html, json, junit and markdown
+    #[arg(long, global = true, value_name = "FORMAT=PATH")]
+    report: Vec<ReportSpec>,
+
+    /// Print one JSON event per line on stdout, moving everything else to stderr
+    #[arg(long, value_enum, default_value_t = MessageFormat::Human)]
+    message_format: MessageFormat,
+
     /// Specify an explict path to the manifest file
     #[arg(long)]
     manifest_path: Option<PathBuf>,
 
     /// Specify a specific package to run matrix against
-    #[arg(long, short)]
+    #[arg(long, short, global = true)]
     package: Option<String>,
 
     /// Split the workspace packages into 'n' chunks of packages
@@ -55,16 +112,37 @@ pub(crate) struct MatrixArgs {
     #[arg(
         long,
         default_value_t = 1,
-        requires = "n_chunks",
+        requires = "num_chunks",
         help = "Which chunk to test, indexed at 1"
     )]
     chunk: usize,
 
+    /// Split the feature sets of the package given with `--package` into the chunks
+    /// instead, which `--package` on its own runs all of
+    #[arg(long, requires = "package")]
+    chunk_features: bool,
+
+    /// Only run this shard of the jobs of every package, i.e. `2/4`. Jobs stay in their
+    /// shard as others are added or removed.
+    #[arg(long, global = true, value_name = "I/N")]
+    shard: Option<Shard>,
+
+    /// Balance the shards by how long each job took in this JSON report of an earlier
+    /// run, rather than by the number of jobs
+    #[arg(long, global = true, value_name = "PATH", requires = "shard")]
+    shard_durations: Option<PathBuf>,
+
     /// The supported cargo subcomand to run
     #[command(subcommand)]
     command: CargoSubcommands,
 }
 
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub(crate) enum MessageFormat {
+    Human,
+    Json,
+}
+
 #[derive(Debug, Subcommand)]
 pub(crate) enum CargoSubcommands {
     /// cargo build
@@ -74,16 +152,225 @@ pub(crate) enum CargoSubcommands {
     /// cargo clippy
     Clippy(VarArgs),
     /// cargo test
-    Test(VarArgs),
+    Test(TestArgs),
     /// cargo llvm-cov
     LlvmCov(VarArgs),
+    /// Any other cargo subcommand that accepts `-p`, `-F` and `--no-default-features`,
+    /// i.e. `run doc --no-deps` or `run nextest run`
+    Run(RunArgs),
+    /// Any other program, i.e. `exec -- wasm-pack build --features={features}`. The
+    /// placeholders `{package}`, `{features}`, `{features_spaced}`, `{features_flag}`
+    /// and `{manifest}` are filled in for each feature set, which is also passed in
+    /// `CARGO_MATRIX_FEATURES`
+    Exec(VarArgs),
+    /// cargo nextest run, building each feature set's test archive once
+    Nextest(NextestArgs),
+    /// Manage the result cache
+    Cache(CacheArgs),
+    /// Write every job of the matrix to a plan, i.e. `plan -o plan.json -- test`, or with
+    /// `--ci`, how to split the matrix across CI jobs
+    Plan(PlanArgs),
+    /// Run the jobs of a plan written by `plan`, i.e. `apply plan.json --shard 2/4`
+    Apply(ApplyArgs),
+    /// Print the feature sets of every package, and how many sets each rule left out
+    List(ListArgs),
+    /// Say why a feature set is or is not in the matrix, i.e. `explain -p foo -F a,b`
+    Explain(ExplainArgs),
+    /// Draw the features of a package and what they turn on, i.e. `graph -p foo --format dot`
+    Graph(GraphArgs),
+    /// Combine the JSON reports of a run split across machines, i.e.
+    /// `merge-reports a.json b.json --plan plan.json --report html=all.html`
+    MergeReports(MergeArgs),
 }
 
 #[derive(Args, Debug, Getters)]
 #[getset(get = "pub(crate)")]
-
 pub(crate) struct VarArgs {
     /// Arguments to pass to the cargo command
     #[clap(trailing_var_arg = true, allow_hyphen_values = true)]
     args: Vec<String>,
 }
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct TestArgs {
+    /// Compile the tests of every feature set with `--no-run` before running any of them,
+    /// reporting the sets that do not compile apart from the ones whose tests fail
+    #[arg(long)]
+    two_phase: bool,
+
+    /// Arguments to pass to cargo test
+    #[command(flatten)]
+    varargs: VarArgs,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct RunArgs {
+    /// The word shown in the job banner, overriding the subcommand config
+    #[arg(long)]
+    verb: Option<String>,
+
+    /// The cargo subcommand followed by its arguments
+    #[command(flatten)]
+    varargs: VarArgs,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct NextestArgs {
+    /// Only run this partition of each feature set's tests, i.e. `count:1/3` or `hash:2/4`
+    #[arg(long, value_name = "SPEC")]
+    partition: Option<String>,
+
+    /// Merge the JUnit reports of every feature set into this file
+    #[arg(long, value_name = "PATH")]
+    junit: Option<PathBuf>,
+
+    /// Arguments to pass to `cargo nextest run`. The ones that say how to build the
+    /// tests, i.e. `--release` or `--target`, go to `cargo nextest archive` instead.
+    #[command(flatten)]
+    varargs: VarArgs,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct CacheArgs {
+    #[command(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(Debug, Subcommand)]
+pub(crate) enum CacheCommand {
+    /// Remove cached results
+    Prune(PruneArgs),
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct PruneArgs {
+    /// Only remove results cached more than this many days ago
+    #[arg(long, value_name = "DAYS")]
+    older_than: Option<u64>,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct PlanArgs {
+    /// The CI service to plan for, instead of writing the jobs
+    #[arg(long, value_enum)]
+    ci: Option<CiProvider>,
+
+    /// Give each CI job up to this many feature sets, splitting larger packages into
+    /// chunks of feature sets
+    #[arg(long, value_name = "N", default_value_t = 20)]
+    jobs_per_shard: usize,
+
+    /// Plan these channels instead of the one given with `--channel`
+    #[arg(long, value_delimiter = ',', value_name = "CHANNEL")]
+    channels: Vec<String>,
+
+    /// Write the plan to this file instead of stdout
+    #[arg(long, short, value_name = "PATH")]
+    output: Option<PathBuf>,
+
+    /// Files the GitLab child pipeline includes, i.e. the one defining `--extends`
+    #[arg(long, value_name = "FILE")]
+    include: Vec<String>,
+
+    /// The job every GitLab job extends, i.e. to set the image
+    #[arg(long, value_name = "JOB")]
+    extends: Option<String>,
+
+    /// The cargo matrix subcommand the jobs run, followed by its arguments
+    #[command(flatten)]
+    varargs: VarArgs,
+}
+
+/// The cargo matrix subcommand of a plan, parsed on its own
+#[derive(Debug, Getters, Parser)]
+#[command(no_binary_name = true)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct PlanCommand {
+    #[command(subcommand)]
+    command: CargoSubcommands,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct ApplyArgs {
+    /// The plan written by `plan -o`
+    plan: PathBuf,
+
+    /// Only run the job with this id. Can be repeated.
+    #[arg(long = "job", value_name = "ID", conflicts_with = "shard")]
+    jobs: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub(crate) enum CiProvider {
+    /// A JSON object with an `include` array, for `strategy.matrix: ${{ fromJSON(...) }}`
+    Github,
+    /// A child pipeline with one job per shard
+    Gitlab,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct MergeArgs {
+    /// The JSON reports to combine
+    #[arg(required = true)]
+    reports: Vec<PathBuf>,
+
+    /// Check that the reports hold every job of this plan once, and no other jobs
+    #[arg(long, value_name = "PATH")]
+    plan: Option<PathBuf>,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct ListArgs {
+    /// How to print the matrix
+    #[arg(long, value_enum, default_value_t = ListFormat::Table)]
+    format: ListFormat,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub(crate) enum ListFormat {
+    /// A grid per package marking the features of each set, and what was pruned
+    Table,
+    /// The sets and pruning counts of every package
+    Json,
+    /// One feature set per line, after its package
+    Plain,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct ExplainArgs {
+    /// The feature set to explain, comma separated
+    #[arg(long, short = 'F', value_delimiter = ',')]
+    features: Vec<String>,
+}
+
+#[derive(Args, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct GraphArgs {
+    /// How to draw the graph
+    #[arg(long, value_enum, default_value_t = GraphFormat::Tree)]
+    format: GraphFormat,
+
+    /// Mark each feature with the results of the sets that have it in this JSON report
+    #[arg(long, value_name = "PATH")]
+    results: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq, ValueEnum)]
+pub(crate) enum GraphFormat {
+    /// An indented tree like `cargo tree`
+    Tree,
+    /// Graphviz
+    Dot,
+    /// A Mermaid flowchart
+    Mermaid,
+}
@@ -0,0 +1,232 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::execute::JobReport;
+use getset::Getters;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt::{Display, Formatter};
+
+/// The first error cargo reported for a job, read from its `--message-format=json`
+/// messages. Failing sets with the same one most likely fail for the same reason.
+#[derive(Clone, Debug, Deserialize, Eq, Getters, Hash, PartialEq, Serialize)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct Diagnostic {
+    /// i.e. `E0425`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    code: Option<String>,
+    message: String,
+    /// `<file>:<line>:<column>` of the primary span
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    span: Option<String>,
+}
+
+impl Diagnostic {
+    /// The diagnostic of a `compiler-message`, if it is an error
+    pub(crate) fn from_message(message: &Value) -> Option<Self> {
+        if message.get("reason")?.as_str()? != "compiler-message" {
+            return None;
+        }
+        let diagnostic = message.get("message")?;
+        if diagnostic.get("level")?.as_str()? != "error" {
+            return None;
+        }
+        let span = diagnostic
+            .get("spans")
+            .and_then(Value::as_array)
+            .and_then(|spans| {
+                spans
+                    .iter()
+                    .find(|span| span.get("is_primary").and_then(Value::as_bool) == Some(true))
+            })
+            .and_then(|span| {
+                Some(format!(
+                    "{}:{}:{}",
+                    span.get("file_name")?.as_str()?,
+                    span.get("line_start")?.as_u64()?,
+                    span.get("column_start")?.as_u64()?
+                ))
+            });
+        Some(Self {
+            code: diagnostic
+                .get("code")
+                .and_then(|code| code.get("code"))
+                .and_then(Value::as_str)
+                .map(str::to_string),
+            message: diagnostic.get("message")?.as_str()?.to_string(),
+            span,
+        })
+    }
+}
+
+impl Display for Diagnostic {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match &self.code {
+            Some(code) => write!(f, "error[{code}]: {}", self.message)?,
+            None => write!(f, "error: {}", self.message)?,
+        }
+        if let Some(span) = &self.span {
+            write!(f, " at {span}")?;
+        }
+        Ok(())
+    }
+}
+
+/// The failing jobs of a package that share a first error, or that have none
+#[derive(Debug)]
+pub(crate) struct Group<'a> {
+    pub(crate) package: &'a str,
+    pub(crate) error: Option<&'a Diagnostic>,
+    pub(crate) reports: Vec<&'a JobReport>,
+}
+
+impl<'a> Group<'a> {
+    /// The job with the fewest features, the quickest way to see the error again
+    pub(crate) fn reproducer(&self) -> Option<&'a JobReport> {
+        self.reports
+            .iter()
+            .copied()
+            .min_by_key(|report| (report.job().feature_set().len(), report.job().feature_set()))
+    }
+}
+
+/// Group the failing jobs by package and first error, the groups with the most jobs first
+pub(crate) fn group(reports: &[JobReport]) -> Vec<Group<'_>> {
+    let mut groups: Vec<Group<'_>> = Vec::new();
+    for report in reports.iter().filter(|r| r.result().is_failure()) {
+        let package = report.job().package().as_str();
+        let error = report.error().as_ref();
+        match groups
+            .iter_mut()
+            .find(|group| group.package == package && group.error == error)
+        {
+            Some(group) => group.reports.push(report),
+            None => groups.push(Group {
+                package,
+                error,
+                reports: vec![report],
+            }),
+        }
+    }
+    groups.sort_by_key(|group| std::cmp::Reverse(group.reports.len()));
+    groups
+}
+
+#[cfg(test)]
+mod test {
+    use super::{group, Diagnostic};
+    use crate::runtime::execute::{Job, JobReport, TaskResult};
+    use serde_json::{json, Value};
+
+    fn compiler_message(message: Value) -> Value {
+        json!({
+            "reason": "compiler-message",
+            "package_id": "path+file:///src/foo#0.1.0",
+            "manifest_path": "/src/foo/Cargo.toml",
+            "message": message,
+        })
+    }
+
+    #[test]
+    fn reads_the_code_message_and_primary_span() {
+        let diagnostic = Diagnostic::from_message(&compiler_message(json!({
+            "level": "error",
+            "message": "cannot find value `x` in this scope",
+            "code": { "code": "E0425", "explanation": "An unresolved name was used." },
+            "spans": [
+                {
+                    "file_name": "src/other.rs",
+                    "line_start": 1,
+                    "column_start": 1,
+                    "is_primary": false,
+                },
+                {
+                    "file_name": "src/lib.rs",
+                    "line_start": 3,
+                    "column_start": 5,
+                    "is_primary": true,
+                },
+            ],
+            "rendered": "error[E0425]: cannot find value `x` in this scope",
+        })))
+        .unwrap();
+        assert_eq!(diagnostic.code().as_deref(), Some("E0425"));
+        assert_eq!(diagnostic.span().as_deref(), Some("src/lib.rs:3:5"));
+        assert_eq!(
+            diagnostic.to_string(),
+            "error[E0425]: cannot find value `x` in this scope at src/lib.rs:3:5"
+        );
+    }
+
+    #[test]
+    fn reads_errors_without_a_code_or_span() {
+        let diagnostic = Diagnostic::from_message(&compiler_message(json!({
+            "level": "error",
+            "message": "a and b are incompatible",
+            "code": null,
+            "spans": [],
+        })))
+        .unwrap();
+        assert_eq!((diagnostic.code(), diagnostic.span()), (&None, &None));
+        assert_eq!(diagnostic.to_string(), "error: a and b are incompatible");
+    }
+
+    #[test]
+    fn ignores_everything_but_errors() {
+        for level in ["warning", "note", "help", "failure-note"] {
+            let message =
+                compiler_message(json!({ "level": level, "message": "unused", "spans": [] }));
+            assert_eq!(Diagnostic::from_message(&message), None, "{level}");
+        }
+        assert_eq!(
+            Diagnostic::from_message(&json!({ "reason": "build-finished", "success": false })),
+            None
+        );
+    }
+
+    fn error(message: &str) -> Option<Diagnostic> {
+        Diagnostic::from_message(&compiler_message(
+            json!({ "level": "error", "message": message }),
+        ))
+    }
+
+    #[test]
+    fn groups_failures_by_package_and_first_error() {
+        let failed = TaskResult::Fail(101);
+        let reports = [
+            JobReport::ran(&Job::check("foo", &["a", "b"]), failed, error("a and b")),
+            JobReport::ran(&Job::check("foo", &[]), TaskResult::Success, None),
+            JobReport::ran(&Job::check("bar", &["a"]), failed, error("a and b")),
+            JobReport::ran(&Job::check("foo", &["b"]), failed, error("a and b")),
+            JobReport::ran(&Job::check("foo", &["c"]), failed, None),
+            JobReport::ran(&Job::check("foo", &["a"]), failed, error("a and b")),
+        ];
+        let groups = group(&reports);
+        let summary: Vec<(&str, Option<String>, usize)> = groups
+            .iter()
+            .map(|group| {
+                (
+                    group.package,
+                    group.error.map(ToString::to_string),
+                    group.reports.len(),
+                )
+            })
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("foo", Some("error: a and b".to_string()), 3),
+                ("bar", Some("error: a and b".to_string()), 1),
+                ("foo", None, 1),
+            ]
+        );
+        // The fewest features, then the first set in order
+        let reproducer = groups[0].reproducer().unwrap();
+        assert_eq!(reproducer.job().feature_set().to_string(), "a");
+    }
+}
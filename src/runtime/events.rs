@@ -0,0 +1,173 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::FeatureSet,
+    runtime::{
+        execute::{Job, JobReport, Stage},
+        report::Entry,
+    },
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::Write,
+    sync::{Mutex, OnceLock},
+};
+
+/// Where events are written once `--message-format json` asked for them
+static EVENTS: OnceLock<Mutex<File>> = OnceLock::new();
+
+/// One line of the `--message-format json` stream
+#[derive(Debug, Serialize)]
+#[serde(tag = "reason", rename_all = "kebab-case")]
+pub(crate) enum Event<'a> {
+    /// Every job the run is going to go through, in order
+    PlanComputed { jobs: Vec<Planned<'a>> },
+    JobStarted {
+        #[serde(flatten)]
+        job: Planned<'a>,
+    },
+    /// A job finished, or was skipped because it passed before
+    JobFinished {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        stage: Option<Stage>,
+        #[serde(flatten)]
+        entry: Entry,
+    },
+    /// A message cargo printed with `--message-format json` while running a job
+    CargoMessage {
+        id: String,
+        message: serde_json::Value,
+    },
+    RunFinished {
+        jobs: usize,
+        passed: usize,
+        failed: usize,
+        interrupted: bool,
+        success: bool,
+    },
+}
+
+impl<'a> Event<'a> {
+    pub(crate) fn job_finished(report: &JobReport) -> Self {
+        Event::JobFinished {
+            stage: report.job().stage(),
+            entry: Entry::from(report),
+        }
+    }
+}
+
+/// A job, by the id of the planned job it was made from when it is not one itself
+#[derive(Debug, Serialize)]
+pub(crate) struct Planned<'a> {
+    id: String,
+    /// Why the plan does not list the job
+    #[serde(skip_serializing_if = "Option::is_none")]
+    stage: Option<Stage>,
+    package: &'a str,
+    channel: &'a str,
+    features: &'a FeatureSet,
+    command: String,
+}
+
+impl<'a> From<&'a Job> for Planned<'a> {
+    fn from(job: &'a Job) -> Self {
+        Self {
+            id: job.planned_id(),
+            stage: job.stage(),
+            package: job.package(),
+            channel: job.channel(),
+            features: job.feature_set(),
+            command: job.command_line(),
+        }
+    }
+}
+
+/// Reserve stdout for events, sending everything else that would be written there,
+/// by us or by the jobs, to stderr instead
+#[cfg(unix)]
+pub(crate) fn enable() -> Result<()> {
+    use nix::unistd::dup2;
+    use std::{
+        io,
+        os::fd::{AsFd, AsRawFd},
+    };
+
+    io::stdout().flush()?;
+    let events = File::from(io::stdout().as_fd().try_clone_to_owned()?);
+    let _ = dup2(io::stderr().as_raw_fd(), io::stdout().as_raw_fd())?;
+    let _ = EVENTS.set(Mutex::new(events));
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub(crate) fn enable() -> Result<()> {
+    Err(anyhow::anyhow!(
+        "--message-format json is only supported on unix"
+    ))
+}
+
+/// Is the event stream on
+pub(crate) fn enabled() -> bool {
+    EVENTS.get().is_some()
+}
+
+/// Write the event as a line of JSON, if the event stream is on
+pub(crate) fn emit(event: &Event<'_>) -> Result<()> {
+    if let Some(events) = EVENTS.get() {
+        let mut line = serde_json::to_vec(event)?;
+        line.push(b'\n');
+        // A panic while holding the lock leaves nothing half written that matters
+        let mut events = events.lock().unwrap_or_else(|e| e.into_inner());
+        events.write_all(&line)?;
+        events.flush()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Event, Planned};
+    use crate::runtime::execute::{Job, JobReport, TaskResult};
+    use serde_json::{json, Value};
+
+    #[test]
+    fn jobs_go_by_their_planned_id() {
+        let job = Job::check("foo", &["a", "b"]);
+        let compile = job.compile_tests();
+        let event = serde_json::to_value(Event::PlanComputed {
+            jobs: vec![Planned::from(&job)],
+        })
+        .unwrap();
+        assert_eq!(event["reason"], "plan-computed");
+        assert_eq!(event["jobs"][0]["id"], job.id());
+        assert_eq!(event["jobs"][0]["features"], json!(["a", "b"]));
+        assert!(event["jobs"][0].get("stage").is_none());
+
+        let event = serde_json::to_value(Event::JobStarted {
+            job: Planned::from(&compile),
+        })
+        .unwrap();
+        assert_eq!(event["reason"], "job-started");
+        assert_eq!(event["id"], job.id());
+        assert_eq!(event["stage"], "compile-tests");
+
+        // The line is written as is, so `id` may only be there once
+        let report = JobReport::ran(&compile, TaskResult::CompileError(101), None);
+        let line = serde_json::to_string(&Event::job_finished(&report)).unwrap();
+        assert_eq!(line.matches("\"id\":").count(), 1, "{line}");
+        let event: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(event["reason"], "job-finished");
+        assert_eq!(event["id"], job.id());
+        assert_eq!(event["stage"], "compile-tests");
+        assert_eq!(event["outcome"], "compile-error");
+        assert_eq!(event["exit_code"], 101);
+    }
+}
@@ -6,142 +6,1201 @@
 // option. All files in the project carrying such notice may not be copied,
 // modified, or distributed except according to those terms.
 
-use crate::feature::FeatureMatrix;
+use crate::{
+    config::Config,
+    feature::{FeatureMatrix, FeatureSet},
+    runtime::{
+        diagnostic::Diagnostic,
+        events::{self, Event},
+        nextest::{self, Nextest},
+        process::{self, Exit, Output, Relay},
+        template,
+    },
+};
 use anyhow::Result;
+use getset::{CopyGetters, Getters, Setters};
 use itertools::Itertools;
 use lazy_static::lazy_static;
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
 use std::{
-    env::var_os,
+    env::{self, var_os},
     ffi::OsString,
-    path::PathBuf,
-    process::{Command, Stdio},
+    fs::File,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+    process::Command,
+    time::{Duration, Instant},
 };
-use yansi::Paint;
+use yansi::{Color, Paint, Painted};
+
+/// How much of a failed job's log is echoed to the console
+const LOG_TAIL_LINES: usize = 20;
 
 lazy_static! {
     static ref CARGO: OsString = var_os("CARGO").unwrap_or_else(|| "cargo".into());
 }
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
 pub(crate) enum TaskKind {
     Build,
     Check,
     Clippy,
     LlvmCov,
     Test,
+    /// Any other cargo subcommand, given as the leading words of the job arguments
+    Custom(Custom),
+    /// A program other than cargo, given as a template in the job arguments
+    Exec,
+    /// `cargo nextest run`, from an archive built once per feature set
+    Nextest(Nextest),
+    /// `cargo test --no-run`, the first phase of a two phase test run
+    CompileTests,
+}
+
+impl TaskKind {
+    fn banner(&self, args: &[String]) -> String {
+        match self {
+            TaskKind::Build => "    Building ".to_string(),
+            TaskKind::Check => "    Checking ".to_string(),
+            TaskKind::Clippy => "      Clippy ".to_string(),
+            TaskKind::LlvmCov => "    Coverage ".to_string(),
+            TaskKind::Test => "     Testing ".to_string(),
+            TaskKind::Custom(custom) => format!("{:>12} ", custom.verb(args)),
+            TaskKind::Exec => "   Executing ".to_string(),
+            TaskKind::Nextest(_) => "     Nextest ".to_string(),
+            TaskKind::CompileTests => "   Compiling ".to_string(),
+        }
+    }
+
+    fn subcommand(&self) -> Option<&'static str> {
+        match self {
+            TaskKind::Build => Some("build"),
+            TaskKind::Check => Some("check"),
+            TaskKind::Clippy => Some("clippy"),
+            TaskKind::LlvmCov => Some("llvm-cov"),
+            TaskKind::Test | TaskKind::CompileTests => Some("test"),
+            TaskKind::Custom(_) | TaskKind::Exec | TaskKind::Nextest(_) => None,
+        }
+    }
+
+    /// What the kind adds to the id of a job, leaving out the paths that depend on the
+    /// machine the job runs on
+    fn identity(&self) -> String {
+        match self {
+            TaskKind::Build => "build".to_string(),
+            TaskKind::Check => "check".to_string(),
+            TaskKind::Clippy => "clippy".to_string(),
+            TaskKind::LlvmCov => "llvm-cov".to_string(),
+            TaskKind::Test => "test".to_string(),
+            TaskKind::Custom(custom) => match &custom.template {
+                Some(template) => format!("custom {}", template.join(" ")),
+                None => "custom".to_string(),
+            },
+            TaskKind::Exec => "exec".to_string(),
+            TaskKind::Nextest(nextest) => match nextest.partition() {
+                Some(partition) => format!("nextest {partition}"),
+                None => "nextest".to_string(),
+            },
+            TaskKind::CompileTests => "compile-tests".to_string(),
+        }
+    }
+
+    /// Does the subcommand take `--message-format json`
+    fn has_messages(&self) -> bool {
+        matches!(
+            self,
+            TaskKind::Build
+                | TaskKind::Check
+                | TaskKind::Clippy
+                | TaskKind::Test
+                | TaskKind::CompileTests
+        )
+    }
+
+    /// Fill in the banner verb and argument template a package configures for a
+    /// custom subcommand, unless they were given on the command line
+    pub(crate) fn for_package(&self, config: &Config, args: &[String]) -> Self {
+        match self {
+            TaskKind::Custom(custom) => {
+                let configured = config.find_subcommand(args);
+                TaskKind::Custom(Custom {
+                    verb: custom
+                        .verb
+                        .clone()
+                        .or_else(|| configured.and_then(|sub| sub.verb().clone())),
+                    template: custom
+                        .template
+                        .clone()
+                        .or_else(|| configured.and_then(|sub| sub.args().clone())),
+                })
+            }
+            kind => kind.clone(),
+        }
+    }
+}
+
+/// Why a run goes through a job its plan does not list
+#[derive(Clone, Copy, Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Stage {
+    /// Compiling the tests of a planned job, the first phase of a two phase run
+    CompileTests,
+    /// Running a subset of the feature set of a failed job, to shrink it
+    Minimize,
+}
+
+/// How a cargo subcommand that cargo-matrix does not know about is run
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Custom {
+    verb: Option<String>,
+    template: Option<Vec<String>>,
+}
+
+impl Custom {
+    pub(crate) fn new(verb: Option<String>) -> Self {
+        Self {
+            verb,
+            template: None,
+        }
+    }
+
+    /// The banner verb, by default the capitalized subcommand, i.e. `Nextest`
+    fn verb(&self, args: &[String]) -> String {
+        if let Some(verb) = &self.verb {
+            return verb.clone();
+        }
+        let subcommand = args.first().map(String::as_str).unwrap_or("Running");
+        let mut chars = subcommand.chars();
+        chars
+            .next()
+            .map(|first| first.to_uppercase().chain(chars).collect())
+            .unwrap_or_default()
+    }
+
+    /// The arguments that select the package, the feature set and the manifest. A
+    /// template places the manifest itself through `{manifest}`, if the tool takes one.
+    fn selection(
+        &self,
+        package: &str,
+        feature_set: &FeatureSet,
+        manifest_path: Option<&Path>,
+    ) -> Vec<String> {
+        match &self.template {
+            Some(template) => template::expand(template, package, feature_set, manifest_path),
+            None => default_selection(package, feature_set, manifest_path),
+        }
+    }
 }
 
 #[derive(Clone, Copy, Debug)]
 pub(crate) enum TaskResult {
     Success,
+    /// The tests did not compile, in a two phase test run
+    CompileError(i32),
+    /// Passed, but only after at least one retry
+    Flaky,
     Fail(i32),
+    Timeout,
+    /// Stopped by SIGINT/SIGTERM before it could finish
+    Interrupted,
+    /// Never started, because the run was interrupted or hit `--max-failures`
+    NotRun,
+    /// Skipped, because it passed in the run being resumed
+    Resumed,
+    /// Skipped, because it passed with the same inputs in an earlier run
+    Cached,
+}
+
+impl TaskResult {
+    pub(crate) fn is_failure(self) -> bool {
+        matches!(
+            self,
+            TaskResult::Fail(_) | TaskResult::CompileError(_) | TaskResult::Timeout
+        )
+    }
+
+    /// Passed now or, for skipped jobs, in an earlier run
+    pub(crate) fn is_pass(self) -> bool {
+        matches!(
+            self,
+            TaskResult::Success | TaskResult::Flaky | TaskResult::Resumed | TaskResult::Cached
+        )
+    }
+
+    pub(crate) fn label(self) -> &'static str {
+        match self {
+            TaskResult::Success => "OK",
+            TaskResult::Flaky => "FLAKY",
+            TaskResult::Fail(_) => "FAILED",
+            TaskResult::CompileError(_) => "COMPILE ERROR",
+            TaskResult::Timeout => "TIMEOUT",
+            TaskResult::Interrupted => "INTERRUPTED",
+            TaskResult::NotRun => "NOT RUN",
+            TaskResult::Resumed => "RESUMED",
+            TaskResult::Cached => "CACHED",
+        }
+    }
+
+    /// The exit code of the cargo process, if it exited on its own
+    pub(crate) fn code(self) -> Option<i32> {
+        match self {
+            TaskResult::Success | TaskResult::Flaky | TaskResult::Resumed | TaskResult::Cached => {
+                Some(0)
+            }
+            TaskResult::Fail(code) | TaskResult::CompileError(code) => Some(code),
+            TaskResult::Timeout | TaskResult::Interrupted | TaskResult::NotRun => None,
+        }
+    }
+
+    pub(crate) fn color(self) -> Color {
+        match self {
+            TaskResult::Success => Color::BrightGreen,
+            TaskResult::Flaky => Color::BrightYellow,
+            TaskResult::Fail(_) | TaskResult::CompileError(_) | TaskResult::Timeout => {
+                Color::BrightRed
+            }
+            TaskResult::Interrupted => Color::Yellow,
+            TaskResult::NotRun => Color::Primary,
+            TaskResult::Resumed | TaskResult::Cached => Color::Green,
+        }
+    }
+
+    fn paint(self) -> Painted<&'static str> {
+        self.label().fg(self.color())
+    }
+}
+
+/// Limits applied to every attempt of a job, and when a failed attempt is tried again
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+pub(crate) struct Policy {
+    #[serde(default, with = "seconds")]
+    timeout: Option<Duration>,
+    #[serde(default)]
+    retries: u32,
+    #[serde(default, with = "pattern")]
+    retry_on: Option<Regex>,
+}
+
+impl Policy {
+    pub(crate) fn new(timeout: Option<Duration>, retries: u32, retry_on: Option<Regex>) -> Self {
+        Self {
+            timeout,
+            retries,
+            retry_on,
+        }
+    }
+
+    fn is_retryable(&self, attempt: u32, output: &[u8]) -> bool {
+        attempt <= self.retries
+            && match &self.retry_on {
+                Some(re) => re.is_match(&String::from_utf8_lossy(output)),
+                None => true,
+            }
+    }
 }
 
 pub(crate) struct Task {
     kind: TaskKind,
     package: String,
+    channel: String,
     matrix: FeatureMatrix,
     manifest_path: Option<PathBuf>,
     args: Vec<String>,
+    policy: Policy,
     dry_run: bool,
 }
 
 impl Task {
+    #[allow(clippy::too_many_arguments)]
     pub(crate) fn new(
         kind: TaskKind,
         package: String,
+        channel: String,
         matrix: FeatureMatrix,
         manifest_path: Option<PathBuf>,
         args: Vec<String>,
+        policy: Policy,
         dry_run: bool,
     ) -> Self {
         Self {
             kind,
             package,
+            channel,
             matrix,
             manifest_path,
             args,
+            policy,
             dry_run,
         }
     }
 
-    pub(crate) fn execute(self) -> Result<TaskResult> {
-        for feature_set in self.matrix {
-            match self.kind {
-                TaskKind::Build => print!("{}", Paint::cyan("    Building ").bold()),
-                TaskKind::Check => print!("{}", Paint::cyan("    Checking ").bold()),
-                TaskKind::Clippy => print!("{}", Paint::cyan("      Clippy ").bold()),
-                TaskKind::LlvmCov => print!("{}", Paint::cyan("    Coverage ").bold()),
-                TaskKind::Test => print!("{}", Paint::cyan("     Testing ").bold()),
-            }
+    /// Expand the task into one job per feature set in the matrix
+    pub(crate) fn jobs(self) -> Vec<Job> {
+        self.matrix
+            .into_iter()
+            .map(|feature_set| Job {
+                kind: self.kind.clone(),
+                package: self.package.clone(),
+                channel: self.channel.clone(),
+                feature_set,
+                manifest_path: self.manifest_path.clone(),
+                args: self.args.clone(),
+                policy: self.policy.clone(),
+                dry_run: self.dry_run,
+                messages: false,
+                planned: None,
+            })
+            .collect()
+    }
+}
 
-            println!("package={} features=[{feature_set}]", self.package,);
+/// Where the console output of a job is sent
+pub(crate) enum Console<'a> {
+    /// Banners go straight to stdout and cargo inherits our stdio
+    Inherit,
+    /// Banners and cargo output are collected so they can be printed in one piece
+    Buffer(&'a mut Vec<u8>),
+}
 
-            let mut cmd = Command::new(CARGO.as_os_str());
+impl Console<'_> {
+    pub(crate) fn writer(&mut self) -> Box<dyn Write + '_> {
+        match self {
+            Console::Inherit => Box::new(io::stdout()),
+            Console::Buffer(buffer) => Box::new(buffer),
+        }
+    }
 
-            let cmd = match self.kind {
-                TaskKind::Build => cmd.arg("build"),
-                TaskKind::Check => cmd.arg("check"),
-                TaskKind::Clippy => cmd.arg("clippy"),
-                TaskKind::LlvmCov => cmd.arg("llvm-cov"),
-                TaskKind::Test => cmd.arg("test"),
-            };
+    /// A console writing to the same place, to hand to a job while keeping this one
+    pub(crate) fn reborrow(&mut self) -> Console<'_> {
+        match self {
+            Console::Inherit => Console::Inherit,
+            Console::Buffer(buffer) => Console::Buffer(buffer),
+        }
+    }
+}
 
-            let on_success = || {
-                println!(
-                    "{} {}",
-                    Paint::cyan("      Result").bold(),
-                    Paint::bright_green("OK")
-                );
-                println!();
-            };
+/// A single cargo invocation for one feature set of a package
+#[derive(Clone, CopyGetters, Debug, Deserialize, Getters, Serialize, Setters)]
+pub(crate) struct Job {
+    #[getset(get = "pub(crate)")]
+    kind: TaskKind,
+    #[getset(get = "pub(crate)")]
+    package: String,
+    #[getset(get = "pub(crate)")]
+    channel: String,
+    #[getset(get = "pub(crate)")]
+    #[serde(rename = "features")]
+    feature_set: FeatureSet,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    manifest_path: Option<PathBuf>,
+    args: Vec<String>,
+    policy: Policy,
+    /// Whether a job only pretends to run is up to the run it is part of
+    #[getset(get_copy = "pub(crate)", set = "pub(crate)")]
+    #[serde(skip)]
+    dry_run: bool,
+    /// Whether cargo's JSON messages are read, to pass them on as events and to find
+    /// the first error, which is also up to the run
+    #[getset(get_copy = "pub(crate)", set = "pub(crate)")]
+    #[serde(skip)]
+    messages: bool,
+    /// The id of the planned job this one was made from, and why
+    #[serde(skip)]
+    planned: Option<(String, Stage)>,
+}
+
+/// The result of a job along with what is needed to report on it
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct JobReport {
+    job: Job,
+    result: TaskResult,
+    duration: Duration,
+    attempts: u32,
+    log: Option<PathBuf>,
+    /// The first error cargo reported, when its messages were read
+    error: Option<Diagnostic>,
+}
 
-            let cmd = cmd
-                .stderr(Stdio::inherit())
-                .stdout(Stdio::inherit())
-                .arg("-p")
-                .arg(self.package.clone())
-                .arg("--no-default-features");
+impl JobReport {
+    /// The report for a job that did not run this time around
+    pub(crate) fn skipped(job: &Job, result: TaskResult) -> Self {
+        Self {
+            job: job.clone(),
+            result,
+            duration: Duration::ZERO,
+            attempts: 0,
+            log: None,
+            error: None,
+        }
+    }
 
-            let cmd = if feature_set.is_empty() {
-                cmd
-            } else {
-                cmd.arg("-F").arg(&feature_set.to_string())
-            };
+    /// The report for a job that ran once, with the first error cargo reported
+    #[cfg(test)]
+    pub(crate) fn ran(job: &Job, result: TaskResult, error: Option<Diagnostic>) -> Self {
+        Self {
+            attempts: 1,
+            error,
+            ..Self::skipped(job, result)
+        }
+    }
+}
+
+impl Job {
+    pub(crate) fn command(&self) -> Command {
+        self.build_command(None)
+    }
 
-            let cmd = if let Some(manifest_path) = &self.manifest_path {
-                cmd.arg("--manifest-path")
-                    .arg(format!("{}", manifest_path.display()))
-            } else {
+    /// The command, optionally asking cargo for JSON messages in this format on stdout
+    fn build_command(&self, messages: Option<&str>) -> Command {
+        let mut cmd = match &self.kind {
+            TaskKind::Exec => return self.exec_command(),
+            TaskKind::Nextest(nextest) => {
+                let mut cmd = Command::new(CARGO.as_os_str());
+                let _ = cmd.args(["nextest", "run"]).args(nextest.run_args(self));
                 cmd
+            }
+            _ => Command::new(CARGO.as_os_str()),
+        };
+
+        if let TaskKind::Nextest(_) = self.kind {
+            let _ = cmd.args(nextest::split_args(&self.args).1);
+        } else if let Some(subcommand) = self.kind.subcommand() {
+            let _ = cmd.arg(subcommand).args(self.selection());
+            if let TaskKind::CompileTests = self.kind {
+                let _ = cmd.arg("--no-run");
+            }
+            if let Some(format) = messages {
+                let _ = cmd.arg(format!("--message-format={format}"));
+            }
+            let _ = cmd.args(&self.args);
+        } else {
+            // The subcommand words lead the arguments, and anything after `--` belongs
+            // to whatever the subcommand runs, so the selection goes in between
+            let split = self
+                .args
+                .iter()
+                .position(|arg| arg == "--")
+                .unwrap_or(self.args.len());
+            let (cargo_args, rest) = self.args.split_at(split);
+            let _ = cmd.args(cargo_args).args(self.selection()).args(rest);
+        }
+        cmd
+    }
+
+    /// The same job, only compiling the tests
+    pub(crate) fn compile_tests(&self) -> Job {
+        Job {
+            kind: TaskKind::CompileTests,
+            planned: Some((self.planned_id(), Stage::CompileTests)),
+            ..self.clone()
+        }
+    }
+
+    /// The same job with another feature set, to minimize it
+    pub(crate) fn with_feature_set(&self, feature_set: FeatureSet) -> Job {
+        Job {
+            feature_set,
+            planned: Some((self.planned_id(), Stage::Minimize)),
+            ..self.clone()
+        }
+    }
+
+    /// The id of the job in the plan. A job the run made from a planned one goes by
+    /// the id of that one, so events and reports only refer to planned jobs.
+    pub(crate) fn planned_id(&self) -> String {
+        match &self.planned {
+            Some((id, _)) => id.clone(),
+            None => self.id(),
+        }
+    }
+
+    /// The job with its manifest relative to the workspace root, so that it can be
+    /// planned in one checkout and applied in another
+    pub(crate) fn relative_to(mut self, workspace_root: &Path) -> Job {
+        self.manifest_path = self.manifest_path.map(|path| {
+            let path = env::current_dir().map_or_else(|_| path.clone(), |cwd| cwd.join(&path));
+            path.strip_prefix(workspace_root)
+                .map(Path::to_path_buf)
+                .unwrap_or(path)
+        });
+        self
+    }
+
+    /// The planned job with its paths in the workspace and target directory of this
+    /// machine
+    pub(crate) fn locate(mut self, workspace_root: &Path, target_directory: &Path) -> Job {
+        self.manifest_path = self.manifest_path.map(|path| workspace_root.join(path));
+        if let TaskKind::Nextest(nextest) = &mut self.kind {
+            nextest.locate(target_directory, workspace_root);
+        }
+        self
+    }
+
+    /// Why the run goes through this job, if the plan does not list it
+    pub(crate) fn stage(&self) -> Option<Stage> {
+        self.planned.as_ref().map(|(_, stage)| *stage)
+    }
+
+    /// A `cargo check` of the feature set on the default channel
+    #[cfg(test)]
+    pub(crate) fn check(package: &str, features: &[&str]) -> Job {
+        Job {
+            kind: TaskKind::Check,
+            package: package.to_string(),
+            channel: "default".to_string(),
+            feature_set: features.iter().copied().map(Into::into).collect(),
+            manifest_path: None,
+            args: Vec::new(),
+            policy: Policy::default(),
+            dry_run: false,
+            messages: false,
+            planned: None,
+        }
+    }
+
+    /// Does this job only compile the tests
+    pub(crate) fn compiles_only(&self) -> bool {
+        matches!(self.kind, TaskKind::CompileTests)
+    }
+
+    /// A short identifier that stays the same as long as what the job runs does, on any
+    /// machine. Where cargo, the target directory and the manifest are is left out.
+    pub(crate) fn id(&self) -> String {
+        let identity = [
+            self.package.as_str(),
+            self.channel.as_str(),
+            &self.kind.identity(),
+            &self.feature_set.to_string(),
+            &self.args.join("\u{1f}"),
+        ]
+        .join("\0");
+        let digest = Sha256::digest(identity);
+        format!("{digest:x}")[..12].to_string()
+    }
+
+    /// A command that has to succeed before the job's command runs, i.e. building the
+    /// nextest archive
+    fn prepare(&self) -> Option<Command> {
+        match &self.kind {
+            TaskKind::Nextest(nextest) => {
+                let mut cmd = Command::new(CARGO.as_os_str());
+                let _ = cmd
+                    .args(["nextest", "archive"])
+                    .args(self.selection())
+                    .args(nextest::split_args(&self.args).0)
+                    .arg("--archive-file")
+                    .arg(nextest.archive(self));
+                Some(cmd)
+            }
+            _ => None,
+        }
+    }
+
+    /// The arguments that select the package, the feature set and the manifest
+    fn selection(&self) -> Vec<String> {
+        match &self.kind {
+            TaskKind::Custom(custom) => custom.selection(
+                &self.package,
+                &self.feature_set,
+                self.manifest_path.as_deref(),
+            ),
+            _ => default_selection(
+                &self.package,
+                &self.feature_set,
+                self.manifest_path.as_deref(),
+            ),
+        }
+    }
+
+    /// The program is the first argument and every argument is a template, with the
+    /// feature set also passed in `CARGO_MATRIX_FEATURES`
+    fn exec_command(&self) -> Command {
+        let mut args = template::expand(
+            &self.args,
+            &self.package,
+            &self.feature_set,
+            self.manifest_path.as_deref(),
+        )
+        .into_iter();
+        let mut cmd = Command::new(args.next().unwrap_or_default());
+        let _ = cmd
+            .args(args)
+            .env("CARGO_MATRIX_PACKAGE", &self.package)
+            .env("CARGO_MATRIX_FEATURES", self.feature_set.to_string());
+        cmd
+    }
+
+    /// The command line that reproduces this job
+    pub(crate) fn command_line(&self) -> String {
+        let cmd = self.command();
+        let command = cmd
+            .get_envs()
+            .filter_map(|(key, value)| {
+                value.map(|value| format!("{}={} ", key.to_string_lossy(), value.to_string_lossy()))
+            })
+            .chain([command_line(&cmd)])
+            .collect();
+        match self.prepare() {
+            Some(prepare) => format!("{} && {command}", command_line(&prepare)),
+            None => command,
+        }
+    }
+
+    /// Run the job, optionally pointing cargo at a dedicated target directory.
+    ///
+    /// When a log file is given, the cargo output is written there and only the
+    /// banner, the result and the tail of the log on failure reach the console.
+    pub(crate) fn execute(
+        &self,
+        console: Console<'_>,
+        log: Option<&Path>,
+        target_dir: Option<&Path>,
+    ) -> Result<JobReport> {
+        let start = Instant::now();
+        let (result, attempts, error) = self.run(console, log, target_dir)?;
+        Ok(JobReport {
+            job: self.clone(),
+            result,
+            duration: start.elapsed(),
+            attempts,
+            log: log.map(Path::to_path_buf),
+            error,
+        })
+    }
+
+    fn run(
+        &self,
+        mut console: Console<'_>,
+        log: Option<&Path>,
+        target_dir: Option<&Path>,
+    ) -> Result<(TaskResult, u32, Option<Diagnostic>)> {
+        // cargo takes one message format, so one given in the arguments wins. The rendered
+        // diagnostics keep their colors only if they end up on a terminal.
+        let messages = self.messages && self.kind.has_messages() && !self.has_message_format();
+        let format = if log.is_none()
+            && io::stdout().is_terminal()
+            && !matches!(var_os("CARGO_TERM_COLOR").as_deref(), Some(color) if color == "never")
+        {
+            "json-diagnostic-rendered-ansi"
+        } else {
+            "json"
+        };
+        let mut prepare = self.prepare();
+        let mut cmd = self.build_command(messages.then_some(format));
+        for cmd in prepare.iter_mut().chain([&mut cmd]) {
+            if let Some(target_dir) = target_dir {
+                let _ = cmd.env("CARGO_TARGET_DIR", target_dir);
+            }
+        }
+
+        {
+            let mut out = console.writer();
+            write!(out, "{}", Paint::cyan(&self.kind.banner(&self.args)).bold())?;
+            writeln!(
+                out,
+                "package={} features=[{}]",
+                self.package, self.feature_set
+            )?;
+            if let Some(prepare) = &prepare {
+                display_command(prepare, &mut out)?;
+            }
+            display_command(&cmd, &mut out)?;
+        }
+
+        if self.dry_run {
+            return Ok((TaskResult::Success, 1, None));
+        }
+
+        // cargo only colors output for a terminal, keep the colors when we replay it
+        if log.is_none()
+            && matches!(console, Console::Buffer(_))
+            && var_os("CARGO_TERM_COLOR").is_none()
+            && io::stdout().is_terminal()
+        {
+            for cmd in prepare.iter_mut().chain([&mut cmd]) {
+                let _ = cmd.env("CARGO_TERM_COLOR", "always");
+            }
+        }
+
+        let mut log_file = if let Some(log) = log {
+            let mut file = File::create(log)?;
+            for cmd in prepare.iter().chain([&cmd]) {
+                writeln!(file, "{}", command_line(cmd))?;
+            }
+            writeln!(file)?;
+            Some(file)
+        } else {
+            None
+        };
+
+        if let TaskKind::Nextest(nextest) = &self.kind {
+            nextest.prepare(self)?;
+        }
+
+        if let Some(prepare) = &mut prepare {
+            let mut captured = Vec::new();
+            let output = self.output(&console, log, &mut captured, None);
+            let exit = process::run(prepare, output, self.policy.timeout)?;
+            if let Some(file) = &mut log_file {
+                file.write_all(&captured)?;
+            }
+            let replay = log.is_none() && matches!(console, Console::Buffer(_));
+            let mut out = console.writer();
+            if replay {
+                out.write_all(&captured)?;
+            }
+            let result = self.result(exit, 1);
+            if !matches!(result, TaskResult::Success) {
+                self.finish(&mut out, result, 1, &captured, log)?;
+                return Ok((result, 1, None));
+            }
+        }
+
+        let mut attempt = 1;
+        loop {
+            let mut captured = Vec::new();
+            let mut error = None;
+            let id = self.planned_id();
+            let mut relay = |line: &[u8]| self.relay(&id, line, &mut error);
+            let output = self.output(&console, log, &mut captured, messages.then_some(&mut relay));
+            let exit = process::run(&mut cmd, output, self.policy.timeout)?;
+
+            if let Some(file) = &mut log_file {
+                if attempt > 1 {
+                    writeln!(file)?;
+                    writeln!(file, "--- attempt {attempt} ---")?;
+                    writeln!(file)?;
+                }
+                file.write_all(&captured)?;
+            }
+
+            let replay = log.is_none() && matches!(console, Console::Buffer(_));
+            let mut out = console.writer();
+            if replay {
+                out.write_all(&captured)?;
+            }
+
+            let result = self.result(exit, attempt);
+            if result.is_failure() && self.policy.is_retryable(attempt, &captured) {
+                writeln!(
+                    out,
+                    "{} attempt {attempt} {}, retrying",
+                    Paint::cyan("    Retrying").bold(),
+                    result.paint()
+                )?;
+                writeln!(out)?;
+                attempt += 1;
+                continue;
+            }
+
+            self.finish(&mut out, result, attempt, &captured, log)?;
+            return Ok((result, attempt, error.filter(|_| result.is_failure())));
+        }
+    }
+
+    /// What an attempt of the job amounts to
+    fn result(&self, exit: Exit, attempt: u32) -> TaskResult {
+        match exit {
+            Exit::Status(status) if status.success() && attempt > 1 => TaskResult::Flaky,
+            Exit::Status(status) if status.success() => TaskResult::Success,
+            Exit::Status(status) if self.compiles_only() => {
+                TaskResult::CompileError(status.code().unwrap_or(-1))
+            }
+            Exit::Status(status) => TaskResult::Fail(status.code().unwrap_or(-1)),
+            Exit::TimedOut => TaskResult::Timeout,
+            Exit::Interrupted => TaskResult::Interrupted,
+        }
+    }
+
+    /// Where the output of a child goes, given where the job output goes
+    fn output<'b>(
+        &self,
+        console: &Console<'_>,
+        log: Option<&Path>,
+        captured: &'b mut Vec<u8>,
+        relay: Option<&'b mut Relay<'b>>,
+    ) -> Output<'b> {
+        if let Some(relay) = relay {
+            return Output::Split {
+                buffer: captured,
+                echo: matches!(console, Console::Inherit) && log.is_none(),
+                relay,
             };
+        }
+        match (console, log, &self.policy.retry_on) {
+            // Nothing needs to look at the output, let cargo have the terminal. A timeout
+            // has to kill whatever cargo started too, which takes a group of its own.
+            (Console::Inherit, None, None) if self.policy.timeout.is_none() => Output::Inherit,
+            (Console::Inherit, None, _) => Output::Capture {
+                buffer: captured,
+                echo: true,
+            },
+            _ => Output::Capture {
+                buffer: captured,
+                echo: false,
+            },
+        }
+    }
 
-            let cmd = cmd.args(self.args.clone());
+    /// Pass a line of cargo's JSON messages on as an event, noting the first error, and
+    /// return what a person would have seen: the rendered diagnostic, or any other
+    /// line as it is
+    fn relay(&self, id: &str, line: &[u8], error: &mut Option<Diagnostic>) -> Result<Vec<u8>> {
+        match serde_json::from_slice::<serde_json::Value>(line) {
+            Ok(message) if message.is_object() => {
+                let rendered = message
+                    .get("message")
+                    .and_then(|m| m.get("rendered"))
+                    .and_then(serde_json::Value::as_str)
+                    .unwrap_or_default()
+                    .as_bytes()
+                    .to_vec();
+                if error.is_none() {
+                    *error = Diagnostic::from_message(&message);
+                }
+                events::emit(&Event::CargoMessage {
+                    id: id.to_string(),
+                    message,
+                })?;
+                Ok(rendered)
+            }
+            _ => Ok(line.to_vec()),
+        }
+    }
 
-            display_command(cmd);
+    /// Do the arguments for cargo, before any `--`, pick a message format
+    fn has_message_format(&self) -> bool {
+        self.args
+            .iter()
+            .take_while(|arg| *arg != "--")
+            .any(|arg| arg == "--message-format" || arg.starts_with("--message-format="))
+    }
 
-            if !self.dry_run {
-                let output = cmd.output()?;
-                if output.status.success() {
-                    on_success();
-                } else {
-                    let code = output.status.code().unwrap_or(-1);
-                    return Ok(TaskResult::Fail(code));
+    /// Write the tail of the log on failure, and the result line
+    fn finish(
+        &self,
+        out: &mut dyn Write,
+        result: TaskResult,
+        attempt: u32,
+        captured: &[u8],
+        log: Option<&Path>,
+    ) -> Result<()> {
+        if result.is_failure() {
+            if let Some(log) = log {
+                writeln!(
+                    out,
+                    "{} {}",
+                    Paint::cyan("         Log").bold(),
+                    log.display()
+                )?;
+                writeln!(out)?;
+                for line in tail(captured, LOG_TAIL_LINES) {
+                    writeln!(out, "{line}")?;
                 }
+                writeln!(out)?;
             }
         }
 
-        Ok(TaskResult::Success)
+        match result {
+            TaskResult::Fail(code) | TaskResult::CompileError(code) => writeln!(
+                out,
+                "{} {} (exit code {code})",
+                Paint::cyan("      Result").bold(),
+                result.paint()
+            )?,
+            TaskResult::Timeout => writeln!(
+                out,
+                "{} {} (killed after {}s)",
+                Paint::cyan("      Result").bold(),
+                result.paint(),
+                self.policy.timeout.unwrap_or_default().as_secs()
+            )?,
+            TaskResult::Flaky => writeln!(
+                out,
+                "{} {} (passed on attempt {attempt})",
+                Paint::cyan("      Result").bold(),
+                result.paint()
+            )?,
+            _ => writeln!(
+                out,
+                "{} {}",
+                Paint::cyan("      Result").bold(),
+                result.paint()
+            )?,
+        }
+        writeln!(out)?;
+        Ok(())
     }
 }
 
-fn display_command(cmd: &Command) {
+/// `-p <package> --no-default-features [-F <features>] [--manifest-path <manifest>]`
+fn default_selection(
+    package: &str,
+    feature_set: &FeatureSet,
+    manifest_path: Option<&Path>,
+) -> Vec<String> {
+    let mut selection = vec![
+        "-p".to_string(),
+        package.to_string(),
+        "--no-default-features".to_string(),
+    ];
+    if !feature_set.is_empty() {
+        selection.push("-F".to_string());
+        selection.push(feature_set.to_string());
+    }
+    if let Some(manifest_path) = manifest_path {
+        selection.push("--manifest-path".to_string());
+        selection.push(format!("{}", manifest_path.display()));
+    }
+    selection
+}
+
+/// The last `count` lines of the captured output
+fn tail(captured: &[u8], count: usize) -> Vec<String> {
+    let text = String::from_utf8_lossy(captured);
+    let lines: Vec<&str> = text.lines().collect();
+    lines[lines.len().saturating_sub(count)..]
+        .iter()
+        .map(ToString::to_string)
+        .collect()
+}
+
+fn command_line(cmd: &Command) -> String {
     let args = cmd.get_args().map(|x| x.to_string_lossy()).join(" ");
-    println!(
-        "{} {} {}",
+    format!("{} {args}", cmd.get_program().to_string_lossy())
+}
+
+fn display_command(cmd: &Command, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(
+        out,
+        "{} {}",
         Paint::cyan("     Running").bold(),
-        cmd.get_program().to_string_lossy(),
-        args
-    );
-    println!();
+        command_line(cmd)
+    )?;
+    writeln!(out)
+}
+
+/// An optional duration as a number of seconds
+mod seconds {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::Duration;
+
+    pub(super) fn serialize<S: Serializer>(
+        value: &Option<Duration>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.map(|d| d.as_secs_f64()).serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Duration>, D::Error> {
+        Ok(Option::<f64>::deserialize(deserializer)?.map(Duration::from_secs_f64))
+    }
+}
+
+/// An optional regex as its pattern
+mod pattern {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer, Serialize, Serializer};
+
+    pub(super) fn serialize<S: Serializer>(
+        value: &Option<Regex>,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        value.as_ref().map(Regex::as_str).serialize(serializer)
+    }
+
+    pub(super) fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Regex>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|pattern| Regex::new(&pattern).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Custom, Job, Policy, Stage, TaskKind};
+    use crate::{feature::FeatureSet, runtime::nextest::Nextest};
+    use std::path::{Path, PathBuf};
+
+    fn job(kind: TaskKind, features: &[&str], args: &[&str]) -> Job {
+        Job {
+            kind,
+            package: "foo".to_string(),
+            channel: "default".to_string(),
+            feature_set: features
+                .iter()
+                .copied()
+                .map(Into::into)
+                .collect::<FeatureSet>(),
+            manifest_path: None,
+            args: args.iter().map(ToString::to_string).collect(),
+            policy: Policy::default(),
+            dry_run: false,
+            messages: false,
+            planned: None,
+        }
+    }
+
+    fn nextest(target: &str, partition: Option<&str>) -> TaskKind {
+        TaskKind::Nextest(Nextest::new(
+            Path::new(target),
+            Path::new("/src/ws"),
+            partition.map(ToString::to_string),
+        ))
+    }
+
+    fn custom(template: Option<&[&str]>) -> Job {
+        let mut job = job(
+            TaskKind::Custom(Custom {
+                verb: None,
+                template: template.map(|args| args.iter().map(ToString::to_string).collect()),
+            }),
+            &["a"],
+            &["udeps"],
+        );
+        job.manifest_path = Some(PathBuf::from("foo/Cargo.toml"));
+        job
+    }
+
+    #[test]
+    fn template_places_the_manifest() {
+        assert_eq!(
+            custom(None).selection(),
+            [
+                "-p",
+                "foo",
+                "--no-default-features",
+                "-F",
+                "a",
+                "--manifest-path",
+                "foo/Cargo.toml"
+            ]
+        );
+        assert_eq!(
+            custom(Some(&["--crate", "{package}", "--features={features}"])).selection(),
+            ["--crate", "foo", "--features=a"]
+        );
+        assert_eq!(
+            custom(Some(&["{package}", "--manifest={manifest}"])).selection(),
+            ["foo", "--manifest=foo/Cargo.toml"]
+        );
+    }
+
+    #[test]
+    fn id_is_the_same_on_every_machine() {
+        // Pinned, so a plan written with one cargo install applies on a runner with another
+        assert_eq!(
+            job(TaskKind::Check, &["a", "b"], &["--locked"]).id(),
+            "c7c2c93c308b"
+        );
+    }
+
+    #[test]
+    fn id_ignores_the_target_directory_and_manifest() {
+        let here = job(nextest("/tmp/ta/target", None), &["a"], &[]);
+        let mut there = job(nextest("/tmp/tb/target", None), &["a"], &[]);
+        there.manifest_path = Some(PathBuf::from("/tmp/tb/Cargo.toml"));
+        assert_ne!(here.command_line(), there.command_line());
+        assert_eq!(here.id(), there.id());
+    }
+
+    #[test]
+    fn planned_jobs_apply_in_another_checkout() {
+        let mut planned = job(nextest("/tmp/ta/target", None), &["a"], &[]);
+        planned.manifest_path = Some(PathBuf::from("/tmp/ta/foo/Cargo.toml"));
+        let plan = serde_json::to_string(&planned.relative_to(Path::new("/tmp/ta"))).unwrap();
+        assert!(!plan.contains("/tmp/ta"), "{plan}");
+
+        let applied = serde_json::from_str::<Job>(&plan)
+            .unwrap()
+            .locate(Path::new("/tmp/tb"), Path::new("/tmp/tb/target"));
+        assert_eq!(
+            applied.manifest_path.as_deref(),
+            Some(Path::new("/tmp/tb/foo/Cargo.toml"))
+        );
+        let command = applied.command_line();
+        assert!(!command.contains("/tmp/ta"), "{command}");
+        assert!(
+            command.contains("/tmp/tb/target/cargo-matrix/nextest/"),
+            "{command}"
+        );
+        assert!(command.contains("--workspace-remap /tmp/tb "), "{command}");
+    }
+
+    #[test]
+    fn derived_jobs_go_by_the_planned_id() {
+        let planned = job(TaskKind::Test, &["a", "b"], &[]);
+        let compile = planned.compile_tests();
+        let subset = compile.with_feature_set(["a"].into_iter().map(Into::into).collect());
+        assert_ne!(compile.id(), planned.id());
+        assert_eq!(compile.planned_id(), planned.id());
+        assert_eq!(subset.planned_id(), planned.id());
+        assert!(planned.stage().is_none());
+        assert!(matches!(compile.stage(), Some(Stage::CompileTests)));
+        assert!(matches!(subset.stage(), Some(Stage::Minimize)));
+    }
+
+    #[test]
+    fn id_changes_with_what_runs() {
+        let base = job(TaskKind::Test, &["a"], &[]).id();
+        for other in [
+            job(TaskKind::Build, &["a"], &[]),
+            job(TaskKind::Test, &["a", "b"], &[]),
+            job(TaskKind::Test, &["a"], &["--release"]),
+            job(nextest("/target", Some("count:1/2")), &["a"], &[]),
+        ] {
+            assert_ne!(base, other.id());
+        }
+        assert_ne!(
+            job(nextest("/target", Some("count:1/2")), &["a"], &[]).id(),
+            job(nextest("/target", Some("count:2/2")), &["a"], &[]).id()
+        );
+    }
+
+    /// Is the process still running, a zombie no one has reaped yet does not count
+    #[cfg(unix)]
+    fn running(pid: i32) -> bool {
+        use nix::{sys::signal::kill, unistd::Pid};
+        use std::fs;
+
+        kill(Pid::from_raw(pid), None).is_ok()
+            && !fs::read_to_string(format!("/proc/{pid}/stat"))
+                .is_ok_and(|stat| stat.contains(") Z "))
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn timeout_kills_the_whole_group() {
+        use super::{Console, TaskResult};
+        use std::{env, fs, thread, time::Duration};
+
+        let pid_file = env::temp_dir().join(format!("cargo-matrix-timeout-{}", std::process::id()));
+        let script = format!("sleep 30 & echo $! > {}; wait", pid_file.display());
+        let mut job = job(TaskKind::Exec, &[], &["sh", "-c", &script]);
+        job.policy = Policy::new(Some(Duration::from_millis(500)), 0, None);
+
+        let report = job.execute(Console::Inherit, None, None).unwrap();
+        assert!(matches!(report.result(), TaskResult::Timeout));
+        // The shell is gone, and the sleep it started went with it
+        let pid: i32 = fs::read_to_string(&pid_file)
+            .unwrap()
+            .trim()
+            .parse()
+            .unwrap();
+        let _ = fs::remove_file(&pid_file);
+        let mut tries = 0;
+        while running(pid) && tries < 20 {
+            thread::sleep(Duration::from_millis(100));
+            tries += 1;
+        }
+        assert!(!running(pid), "the sleep {pid} outlived its job");
+    }
 }
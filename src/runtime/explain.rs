@@ -0,0 +1,111 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::{Closure, Feature, FeatureMatrix, FeatureSet},
+    runtime::{
+        cli::{ExplainArgs, MatrixArgs},
+        generate_config, get_workspace_members,
+    },
+};
+use anyhow::{anyhow, Context, Result};
+use cargo_metadata::{Metadata, Package};
+use yansi::Paint;
+
+/// Say whether a feature set is in the matrix of a package, which rules keep it out if
+/// not, and what cargo turns on for it
+pub(crate) fn run(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    explain_args: &ExplainArgs,
+) -> Result<()> {
+    let channel = matrix_args.channel().as_deref().unwrap_or("default");
+    let package = select_package(matrix_args, metadata)?;
+    let (package, config) = generate_config(package)
+        .with_context(|| format!("invalid cargo-matrix config for {}", package.name))?;
+    let set: FeatureSet = explain_args
+        .features()
+        .iter()
+        .map(|feature| Feature::from(feature.trim()))
+        .filter(|feature| !feature.is_empty())
+        .collect();
+
+    println!(
+        "{} {} [{set}] on channel '{channel}'",
+        Paint::cyan("     Explain").bold(),
+        package.name
+    );
+    let removals = FeatureMatrix::explain(package, &config, channel, &set)?;
+    if removals.is_empty() {
+        println!(
+            "{} {}",
+            Paint::cyan("      Result").bold(),
+            Paint::green("in the matrix")
+        );
+    } else {
+        println!(
+            "{} {}",
+            Paint::cyan("      Result").bold(),
+            Paint::red("not in the matrix")
+        );
+        for removal in &removals {
+            println!("{} {removal}", Paint::cyan("      Reason").bold());
+        }
+    }
+
+    let closure = Closure::new(package, &set);
+    print_reached("    Features", closure.features());
+    print_reached("Dependencies", closure.dependencies());
+    print_reached("Dep features", closure.dependency_features());
+
+    // Deny only looks at the features named in a set, not the ones they turn on
+    for feature in config.always_deny(channel)?.iter() {
+        if let Some(path) = closure.path(feature) {
+            if path.len() > 1 {
+                println!(
+                    "{} turns on always_deny entry `{feature}` via {}, which deny does not check",
+                    Paint::cyan("        Note").bold(),
+                    path.join(" -> ")
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The package given with `--package`, or the only one in the workspace
+pub(crate) fn select_package<'a>(
+    matrix_args: &MatrixArgs,
+    metadata: &'a Metadata,
+) -> Result<&'a Package> {
+    if let Some(name) = matrix_args.package() {
+        return get_workspace_members(metadata)
+            .find(|package| package.name == *name)
+            .ok_or_else(|| anyhow!("{name} is not a member of the workspace"));
+    }
+    let mut members = get_workspace_members(metadata);
+    match (members.next(), members.next()) {
+        (Some(package), None) => Ok(package),
+        _ => Err(anyhow!(
+            "the workspace has several packages, pick one with --package"
+        )),
+    }
+}
+
+/// One line listing what was reached, and through what when it was not asked for
+fn print_reached<'a>(label: &str, reached: impl Iterator<Item = (&'a str, &'a [String])>) {
+    let items: Vec<String> = reached
+        .map(|(name, path)| match path {
+            [_] => name.to_string(),
+            _ => format!("{name} (via {})", path[..path.len() - 1].join(" -> ")),
+        })
+        .collect();
+    if !items.is_empty() {
+        println!("{} {}", Paint::cyan(label).bold(), items.join(", "));
+    }
+}
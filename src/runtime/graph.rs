@@ -0,0 +1,528 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::{Feature, FeatureMatrix},
+    runtime::{
+        cli::{GraphArgs, GraphFormat, MatrixArgs},
+        explain::select_package,
+        report::Report,
+    },
+};
+use anyhow::Result;
+use cargo_metadata::{Metadata, Package};
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+};
+
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+enum Kind {
+    Feature,
+    Dependency,
+}
+
+#[derive(Debug)]
+struct Node {
+    name: String,
+    kind: Kind,
+    /// The feature cargo made for an optional dependency, i.e. `foo = ["dep:foo"]`
+    implicit: bool,
+    /// Starts with `__`, left out of the matrix unless included
+    hidden: bool,
+    /// How many of the sets with this feature failed, and how many ran, in a report
+    results: Option<(usize, usize)>,
+}
+
+impl Node {
+    fn markers(&self) -> String {
+        let mut markers = String::new();
+        if self.implicit {
+            markers.push_str(" (implicit)");
+        }
+        if self.hidden {
+            markers.push_str(" (hidden)");
+        }
+        match self.results {
+            Some((0, total)) => markers.push_str(&format!(" [{total} passed]")),
+            Some((failed, total)) => {
+                markers.push_str(&format!(" [{failed} of {total} failed]"));
+            }
+            None => {}
+        }
+        markers
+    }
+}
+
+#[derive(Debug)]
+struct Edge {
+    from: usize,
+    to: usize,
+    /// The feature of the dependency that is turned on, if any
+    feature: Option<String>,
+    /// `foo?/bar`, which does not turn on the dependency itself
+    weak: bool,
+}
+
+impl Edge {
+    /// How the `[features]` table spells the edge
+    fn spelling(&self, to: &Node) -> String {
+        match (&self.feature, to.kind) {
+            (Some(feature), _) if self.weak => format!("{}?/{feature}", to.name),
+            (Some(feature), _) => format!("{}/{feature}", to.name),
+            (None, Kind::Dependency) => format!("dep:{}", to.name),
+            (None, Kind::Feature) => to.name.clone(),
+        }
+    }
+}
+
+/// The features of a package, what each of them turns on, and the optional dependencies
+#[derive(Debug, Default)]
+struct Graph {
+    nodes: Vec<Node>,
+    edges: Vec<Edge>,
+}
+
+impl Graph {
+    fn new(package: &Package) -> Self {
+        let implicit = FeatureMatrix::find_implicits(package);
+        let mut graph = Self::default();
+        for name in package.features.keys() {
+            let _ = graph.node(name, Kind::Feature, implicit.contains(&Feature::from(name)));
+        }
+        for dependency in package.dependencies.iter().filter(|d| d.optional) {
+            let name = dependency.rename.as_deref().unwrap_or(&dependency.name);
+            let _ = graph.node(name, Kind::Dependency, false);
+        }
+
+        for (name, enables) in &package.features {
+            let from = graph.node(name, Kind::Feature, false);
+            for value in enables {
+                let edge = if let Some(dependency) = value.strip_prefix("dep:") {
+                    Edge {
+                        from,
+                        to: graph.node(dependency, Kind::Dependency, false),
+                        feature: None,
+                        weak: false,
+                    }
+                } else if let Some((dependency, feature)) = value.split_once('/') {
+                    let weak = dependency.ends_with('?');
+                    let dependency = dependency.trim_end_matches('?');
+                    Edge {
+                        from,
+                        to: graph.node(dependency, Kind::Dependency, false),
+                        feature: Some(feature.to_string()),
+                        weak,
+                    }
+                } else {
+                    Edge {
+                        from,
+                        to: graph.node(value, Kind::Feature, false),
+                        feature: None,
+                        weak: false,
+                    }
+                };
+                graph.edges.push(edge);
+            }
+        }
+        graph
+    }
+
+    /// The index of the node, added if it is not there yet
+    fn node(&mut self, name: &str, kind: Kind, implicit: bool) -> usize {
+        if let Some(index) = self
+            .nodes
+            .iter()
+            .position(|node| node.name == name && node.kind == kind)
+        {
+            return index;
+        }
+        self.nodes.push(Node {
+            name: name.to_string(),
+            kind,
+            implicit,
+            hidden: kind == Kind::Feature && name.starts_with("__"),
+            results: None,
+        });
+        self.nodes.len() - 1
+    }
+
+    /// Mark every feature with the results of the sets of the package that have it
+    fn overlay(&mut self, package: &str, report: &Report) {
+        for node in self.nodes.iter_mut().filter(|n| n.kind == Kind::Feature) {
+            let feature = Feature::from(node.name.as_str());
+            let (mut failed, mut total) = (0, 0);
+            for entry in report
+                .jobs()
+                .iter()
+                .filter(|entry| entry.package() == package && entry.features().contains(&feature))
+            {
+                if entry.result().is_failure() {
+                    failed += 1;
+                    total += 1;
+                } else if entry.result().is_pass() {
+                    total += 1;
+                }
+            }
+            if total > 0 {
+                node.results = Some((failed, total));
+            }
+        }
+    }
+
+    fn children(&self, node: usize) -> impl Iterator<Item = &Edge> {
+        self.edges.iter().filter(move |edge| edge.from == node)
+    }
+}
+
+/// Draw the features of a package and what they turn on
+pub(crate) fn run(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    graph_args: &GraphArgs,
+) -> Result<()> {
+    let package = select_package(matrix_args, metadata)?;
+    let mut graph = Graph::new(package);
+    if let Some(path) = graph_args.results() {
+        graph.overlay(&package.name, &Report::read(path)?);
+    }
+
+    let mut out = io::stdout().lock();
+    match graph_args.format() {
+        GraphFormat::Tree => tree(&mut out, &graph, &package.name)?,
+        GraphFormat::Dot => dot(&mut out, &graph, &package.name)?,
+        GraphFormat::Mermaid => mermaid(&mut out, &graph)?,
+    }
+    Ok(())
+}
+
+/// Like `cargo tree`, every feature no other feature turns on is a root, and a feature
+/// already drawn is marked `(*)` instead of being drawn again
+fn tree(out: &mut dyn Write, graph: &Graph, package: &str) -> Result<()> {
+    writeln!(out, "{package}")?;
+    let enabled: BTreeSet<usize> = graph
+        .edges
+        .iter()
+        .filter(|edge| graph.nodes[edge.from].kind == Kind::Feature)
+        .map(|edge| edge.to)
+        .collect();
+    // Cargo rejects features that turn each other on, so every feature is under a root
+    let roots: Vec<usize> = (0..graph.nodes.len())
+        .filter(|node| !enabled.contains(node))
+        .collect();
+    let mut drawn = BTreeSet::new();
+    for (position, root) in roots.iter().enumerate() {
+        let last = position + 1 == roots.len();
+        let label = graph.nodes[*root].name.clone();
+        branch(out, graph, *root, &label, "", last, &mut drawn)?;
+    }
+    Ok(())
+}
+
+fn branch(
+    out: &mut dyn Write,
+    graph: &Graph,
+    node: usize,
+    label: &str,
+    prefix: &str,
+    last: bool,
+    drawn: &mut BTreeSet<usize>,
+) -> Result<()> {
+    let (tee, indent) = if last {
+        ("└── ", "    ")
+    } else {
+        ("├── ", "│   ")
+    };
+    let repeated = !drawn.insert(node) && graph.children(node).next().is_some();
+    writeln!(
+        out,
+        "{prefix}{tee}{label}{}{}",
+        graph.nodes[node].markers(),
+        if repeated { " (*)" } else { "" }
+    )?;
+    if repeated {
+        return Ok(());
+    }
+    let children: Vec<&Edge> = graph.children(node).collect();
+    let prefix = format!("{prefix}{indent}");
+    for (position, edge) in children.iter().enumerate() {
+        let label = edge.spelling(&graph.nodes[edge.to]);
+        let last = position + 1 == children.len();
+        branch(out, graph, edge.to, &label, &prefix, last, drawn)?;
+    }
+    Ok(())
+}
+
+fn dot(out: &mut dyn Write, graph: &Graph, package: &str) -> Result<()> {
+    writeln!(out, "digraph {} {{", quote(package))?;
+    writeln!(out, "    rankdir=LR;")?;
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let mut attributes = vec![format!(
+            "label={}",
+            quote(&format!("{}{}", node.name, node.markers()))
+        )];
+        attributes.push(match node.kind {
+            Kind::Feature => "shape=box".to_string(),
+            Kind::Dependency => "shape=ellipse".to_string(),
+        });
+        let mut styles = Vec::new();
+        if node.implicit || node.hidden {
+            styles.push("dashed");
+        }
+        match node.results {
+            Some((0, _)) => {
+                styles.push("filled");
+                attributes.push("fillcolor=\"#2da44e\"".to_string());
+            }
+            Some(_) => {
+                styles.push("filled");
+                attributes.push("fillcolor=\"#cf222e\"".to_string());
+            }
+            None => {}
+        }
+        if !styles.is_empty() {
+            attributes.push(format!("style={}", quote(&styles.join(","))));
+        }
+        writeln!(out, "    n{index} [{}];", attributes.join(", "))?;
+    }
+    for edge in &graph.edges {
+        let mut attributes = Vec::new();
+        if let Some(feature) = &edge.feature {
+            attributes.push(format!("label={}", quote(feature)));
+        }
+        if edge.weak {
+            attributes.push("style=dashed".to_string());
+        }
+        if attributes.is_empty() {
+            writeln!(out, "    n{} -> n{};", edge.from, edge.to)?;
+        } else {
+            writeln!(
+                out,
+                "    n{} -> n{} [{}];",
+                edge.from,
+                edge.to,
+                attributes.join(", ")
+            )?;
+        }
+    }
+    writeln!(out, "}}")?;
+    Ok(())
+}
+
+fn mermaid(out: &mut dyn Write, graph: &Graph) -> Result<()> {
+    writeln!(out, "graph LR")?;
+    for (index, node) in graph.nodes.iter().enumerate() {
+        let label = format!(
+            "\"{}\"",
+            format!("{}{}", node.name, node.markers()).replace('"', "#quot;")
+        );
+        match node.kind {
+            Kind::Feature => writeln!(out, "    n{index}[{label}]")?,
+            Kind::Dependency => writeln!(out, "    n{index}([{label}])")?,
+        }
+        let class = match node.results {
+            Some((0, _)) => Some("pass"),
+            Some(_) => Some("fail"),
+            None if node.implicit || node.hidden => Some("implicit"),
+            None => None,
+        };
+        if let Some(class) = class {
+            writeln!(out, "    class n{index} {class}")?;
+        }
+    }
+    for edge in &graph.edges {
+        let arrow = if edge.weak { "-.->" } else { "-->" };
+        match &edge.feature {
+            Some(feature) => writeln!(
+                out,
+                "    n{} {arrow}|{}| n{}",
+                edge.from,
+                quote(feature),
+                edge.to
+            )?,
+            None => writeln!(out, "    n{} {arrow} n{}", edge.from, edge.to)?,
+        }
+    }
+    writeln!(out, "    classDef pass fill:#2da44e,color:#fff")?;
+    writeln!(out, "    classDef fail fill:#cf222e,color:#fff")?;
+    writeln!(out, "    classDef implicit stroke-dasharray:4")?;
+    Ok(())
+}
+
+fn quote(text: &str) -> String {
+    format!("\"{}\"", text.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{dot, mermaid, tree, Graph};
+    use crate::{
+        feature::fixture,
+        runtime::{
+            execute::TaskResult,
+            report::{Entry, Report},
+        },
+    };
+
+    fn graph() -> Graph {
+        let mut package = fixture("deny");
+        // The fixture has no dependency features, weak or not, and no feature turned on
+        // by two others
+        let _ = package.features.insert(
+            "feat-e".to_string(),
+            vec!["temp-env/std".to_string(), "rand?/std".to_string()],
+        );
+        let _ = package
+            .features
+            .insert("feat-f".to_string(), vec!["feat-b".to_string()]);
+        Graph::new(&package)
+    }
+
+    fn render(draw: impl FnOnce(&mut Vec<u8>) -> anyhow::Result<()>) -> String {
+        let mut out = Vec::new();
+        draw(&mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn draws_a_tree() {
+        let graph = graph();
+        let out = render(|out| tree(out, &graph, "sample"));
+        assert_eq!(
+            out,
+            r#"sample
+├── __vergen_test (hidden)
+├── feat-a
+│   └── feat-b
+│       └── rand (implicit)
+│           └── dep:rand
+├── feat-c
+│   └── feat-d
+├── feat-e
+│   ├── temp-env/std
+│   └── rand?/std
+├── feat-f
+│   └── feat-b (*)
+├── temp-env (implicit)
+│   └── dep:temp-env
+└── unstable
+"#
+        );
+    }
+
+    #[test]
+    fn draws_dot() {
+        let graph = graph();
+        let out = render(|out| dot(out, &graph, "sample"));
+        assert_eq!(
+            out,
+            r##"digraph "sample" {
+    rankdir=LR;
+    n0 [label="__vergen_test (hidden)", shape=box, style="dashed"];
+    n1 [label="feat-a", shape=box];
+    n2 [label="feat-b", shape=box];
+    n3 [label="feat-c", shape=box];
+    n4 [label="feat-d", shape=box];
+    n5 [label="feat-e", shape=box];
+    n6 [label="feat-f", shape=box];
+    n7 [label="rand (implicit)", shape=box, style="dashed"];
+    n8 [label="temp-env (implicit)", shape=box, style="dashed"];
+    n9 [label="unstable", shape=box];
+    n10 [label="rand", shape=ellipse];
+    n11 [label="temp-env", shape=ellipse];
+    n1 -> n2;
+    n2 -> n7;
+    n3 -> n4;
+    n5 -> n11 [label="std"];
+    n5 -> n10 [label="std", style=dashed];
+    n6 -> n2;
+    n7 -> n10;
+    n8 -> n11;
+}
+"##
+        );
+    }
+
+    #[test]
+    fn draws_mermaid() {
+        let graph = graph();
+        let out = render(|out| mermaid(out, &graph));
+        assert_eq!(
+            out,
+            r#"graph LR
+    n0["__vergen_test (hidden)"]
+    class n0 implicit
+    n1["feat-a"]
+    n2["feat-b"]
+    n3["feat-c"]
+    n4["feat-d"]
+    n5["feat-e"]
+    n6["feat-f"]
+    n7["rand (implicit)"]
+    class n7 implicit
+    n8["temp-env (implicit)"]
+    class n8 implicit
+    n9["unstable"]
+    n10(["rand"])
+    n11(["temp-env"])
+    n1 --> n2
+    n2 --> n7
+    n3 --> n4
+    n5 -->|"std"| n11
+    n5 -.->|"std"| n10
+    n6 --> n2
+    n7 --> n10
+    n8 --> n11
+    classDef pass fill:#2da44e,color:#fff
+    classDef fail fill:#cf222e,color:#fff
+    classDef implicit stroke-dasharray:4
+"#
+        );
+    }
+
+    #[test]
+    fn overlays_the_results() {
+        let mut graph = graph();
+        let report = Report::from_entries(vec![
+            Entry::of(&["feat-a"], TaskResult::Success, 1),
+            Entry::of(&["feat-a", "feat-c"], TaskResult::Fail(101), 1),
+            Entry::of(&["feat-c"], TaskResult::Flaky, 2),
+            Entry::of(&["feat-d"], TaskResult::NotRun, 0),
+            Entry::of(&["unstable"], TaskResult::Success, 1),
+        ]);
+        graph.overlay("foo", &report);
+        let out = render(|out| dot(out, &graph, "sample"));
+        assert_eq!(
+            out,
+            r##"digraph "sample" {
+    rankdir=LR;
+    n0 [label="__vergen_test (hidden)", shape=box, style="dashed"];
+    n1 [label="feat-a [1 of 2 failed]", shape=box, fillcolor="#cf222e", style="filled"];
+    n2 [label="feat-b", shape=box];
+    n3 [label="feat-c [1 of 2 failed]", shape=box, fillcolor="#cf222e", style="filled"];
+    n4 [label="feat-d", shape=box];
+    n5 [label="feat-e", shape=box];
+    n6 [label="feat-f", shape=box];
+    n7 [label="rand (implicit)", shape=box, style="dashed"];
+    n8 [label="temp-env (implicit)", shape=box, style="dashed"];
+    n9 [label="unstable [1 passed]", shape=box, fillcolor="#2da44e", style="filled"];
+    n10 [label="rand", shape=ellipse];
+    n11 [label="temp-env", shape=ellipse];
+    n1 -> n2;
+    n2 -> n7;
+    n3 -> n4;
+    n5 -> n11 [label="std"];
+    n5 -> n10 [label="std", style=dashed];
+    n6 -> n2;
+    n7 -> n10;
+    n8 -> n11;
+}
+"##
+        );
+    }
+}
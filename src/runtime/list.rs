@@ -0,0 +1,125 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::{Feature, FeatureMatrix, Pruning},
+    runtime::{
+        cli::{ListArgs, ListFormat, MatrixArgs},
+        generate_config, get_workspace_members,
+    },
+};
+use anyhow::{Context, Result};
+use cargo_metadata::Metadata;
+use serde::Serialize;
+use std::{
+    collections::BTreeSet,
+    io::{self, Write},
+};
+use yansi::Paint;
+
+/// The matrix of one package on one channel
+#[derive(Debug, Serialize)]
+struct Listing<'a> {
+    package: &'a str,
+    channel: &'a str,
+    sets: FeatureMatrix,
+    pruning: Pruning,
+}
+
+/// Print the feature sets of every selected package without running anything
+pub(crate) fn run(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    list_args: &ListArgs,
+) -> Result<()> {
+    let channel = matrix_args.channel().as_deref().unwrap_or("default");
+    let mut listings = Vec::new();
+    let packages = get_workspace_members(metadata).filter(|package| match matrix_args.package() {
+        Some(name) => package.name == *name,
+        None => true,
+    });
+    for package in packages {
+        let (package, config) = generate_config(package)
+            .with_context(|| format!("invalid cargo-matrix config for {}", package.name))?;
+        let (sets, pruning) = FeatureMatrix::pruned(package, &config, channel)
+            .with_context(|| format!("cannot build the matrix of {}", package.name))?;
+        listings.push(Listing {
+            package: &package.name,
+            channel,
+            sets,
+            pruning,
+        });
+    }
+
+    let mut out = io::stdout().lock();
+    match list_args.format() {
+        ListFormat::Json => {
+            serde_json::to_writer_pretty(&mut out, &listings)?;
+            writeln!(out)?;
+        }
+        ListFormat::Plain => {
+            for listing in &listings {
+                for set in listing.sets.iter() {
+                    writeln!(out, "{}\t{set}", listing.package)?;
+                }
+            }
+        }
+        ListFormat::Table => {
+            for listing in &listings {
+                table(&mut out, listing)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// A row per feature set marking the features in it, followed by what was pruned
+fn table(out: &mut dyn Write, listing: &Listing<'_>) -> Result<()> {
+    writeln!(
+        out,
+        "{} {} ({}), {} feature set(s)",
+        Paint::cyan("     Package").bold(),
+        listing.package,
+        listing.channel,
+        listing.sets.len()
+    )?;
+    writeln!(out)?;
+
+    let features: BTreeSet<&Feature> = listing.sets.iter().flat_map(|set| set.iter()).collect();
+    if features.is_empty() {
+        writeln!(out, "(none)")?;
+    } else {
+        let header: Vec<&str> = features.iter().map(|feature| feature.as_str()).collect();
+        writeln!(out, "{}", Paint::new(header.join("  ")).bold())?;
+        for set in listing.sets.iter() {
+            let row: Vec<String> = features
+                .iter()
+                .map(|feature| {
+                    let mark = if set.contains(*feature) { "x" } else { "-" };
+                    format!("{mark:<width$}", width = feature.len())
+                })
+                .collect();
+            writeln!(out, "{}", row.join("  ").trim_end())?;
+        }
+    }
+    writeln!(out)?;
+
+    let pruning = listing.pruning;
+    writeln!(
+        out,
+        "{} {} implicit, {} hidden, {} deny, {} skip, {} duplicate",
+        Paint::cyan("      Pruned").bold(),
+        pruning.implicit(),
+        pruning.hidden(),
+        pruning.deny(),
+        pruning.skip(),
+        pruning.duplicate()
+    )?;
+    writeln!(out)?;
+    Ok(())
+}
@@ -0,0 +1,153 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::execute::{Job, JobReport};
+use anyhow::Result;
+use std::{
+    fs::{self, File},
+    io::{BufWriter, Write},
+    path::{Path, PathBuf},
+};
+
+/// Longest file name we generate, well below the common 255 byte limit
+const MAX_FILE_NAME: usize = 200;
+
+/// A directory holding one log file per job and an index of them all
+#[derive(Clone, Debug)]
+pub(crate) struct LogDir {
+    path: PathBuf,
+}
+
+impl LogDir {
+    /// Create the directory, or clear out the logs and index of an earlier run so they
+    /// are not taken for this one's. Anything else in it is left alone.
+    pub(crate) fn create(path: &Path) -> Result<Self> {
+        fs::create_dir_all(path)?;
+        for entry in fs::read_dir(path)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() && is_ours(&entry.file_name().to_string_lossy()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+        })
+    }
+
+    /// The log file for the job at `index` in the plan, named after its package, channel
+    /// and feature set.
+    pub(crate) fn job_log(&self, index: usize, job: &Job) -> PathBuf {
+        let mut name = format!("{index:04}-{}", job_name(job));
+        name.truncate(MAX_FILE_NAME);
+        // The compile phase of a two phase test run gets a log of its own
+        let phase = if job.compiles_only() { ".build" } else { "" };
+        self.path.join(format!("{name}{phase}.log"))
+    }
+
+    /// Write `index.txt`, listing the result and log file of every job that ran
+    pub(crate) fn write_index(&self, reports: &[JobReport]) -> Result<()> {
+        let mut index = BufWriter::new(File::create(self.path.join("index.txt"))?);
+        writeln!(
+            index,
+            "result\texit\tduration\tattempts\tpackage\tchannel\tfeatures\tlog"
+        )?;
+        for report in reports {
+            let result = report.result().label().to_lowercase();
+            let exit = report
+                .result()
+                .code()
+                .map_or_else(|| "-".to_string(), |code| code.to_string());
+            let log = report
+                .log()
+                .as_deref()
+                .and_then(Path::file_name)
+                .map(|name| name.to_string_lossy().to_string())
+                .unwrap_or_default();
+            writeln!(
+                index,
+                "{result}\t{exit}\t{:.1}s\t{}\t{}\t{}\t{}\t{log}",
+                report.duration().as_secs_f64(),
+                report.attempts(),
+                report.job().package(),
+                report.job().channel(),
+                report.job().feature_set(),
+            )?;
+        }
+        index.flush()?;
+        Ok(())
+    }
+}
+
+/// A file name for the job made up of its package, channel and feature set
+pub(crate) fn job_name(job: &Job) -> String {
+    let features = if job.feature_set().is_empty() {
+        "no-features".to_string()
+    } else {
+        job.feature_set()
+            .iter()
+            .map(|f| f.0.as_str())
+            .collect::<Vec<_>>()
+            .join("+")
+    };
+    let mut name = sanitize(&format!("{}-{}-{features}", job.package(), job.channel()));
+    name.truncate(MAX_FILE_NAME);
+    name
+}
+
+/// Is this the name of the index or of a log named by `LogDir::job_log`
+fn is_ours(name: &str) -> bool {
+    name == "index.txt"
+        || name.strip_suffix(".log").is_some_and(|name| {
+            name.split_once('-').is_some_and(|(index, _)| {
+                index.len() >= 4 && index.bytes().all(|b| b.is_ascii_digit())
+            })
+        })
+}
+
+/// Replace anything that is not safe in a file name on every platform
+fn sanitize(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '+' | '.') {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::LogDir;
+    use std::{env, fs};
+
+    #[test]
+    fn clears_the_logs_of_an_earlier_run() {
+        let dir = env::temp_dir().join(format!("cargo-matrix-logs-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for name in [
+            "0003-foo-default-a.log",
+            "0012-foo-default-a+b.build.log",
+            "index.txt",
+            "notes.log",
+            "README.md",
+        ] {
+            fs::write(dir.join(name), "").unwrap();
+        }
+
+        let _ = LogDir::create(&dir).unwrap();
+        let mut left: Vec<String> = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .collect();
+        left.sort();
+        assert_eq!(left, ["README.md", "notes.log"]);
+        let _ = fs::remove_dir_all(dir);
+    }
+}
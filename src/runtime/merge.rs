@@ -0,0 +1,167 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::{
+    cli::{MatrixArgs, MergeArgs},
+    plan,
+    report::{Entry, Report},
+};
+use anyhow::{anyhow, Result};
+use std::collections::{HashMap, HashSet};
+use yansi::Paint;
+
+/// Combine the JSON reports of a run split across machines into one, failing unless
+/// every job ran exactly once and passed
+pub(crate) fn run(matrix_args: &MatrixArgs, merge_args: &MergeArgs) -> Result<()> {
+    let reports = merge_args
+        .reports()
+        .iter()
+        .map(|path| Report::read(path))
+        .collect::<Result<Vec<Report>>>()?;
+    let (mut merged, duplicates) = merge(&reports);
+    let seen: HashSet<&String> = merged.iter().map(Entry::id).collect();
+
+    let (mut missing, mut unexpected) = (Vec::new(), Vec::new());
+    if let Some(path) = merge_args.plan() {
+        let plan = plan::read(path)?;
+        let planned: HashSet<&String> = plan.jobs().iter().map(|p| p.id()).collect();
+        unexpected = merged
+            .iter()
+            .filter(|entry| !planned.contains(entry.id()))
+            .cloned()
+            .collect();
+        missing = plan
+            .jobs()
+            .iter()
+            .filter(|planned| !seen.contains(planned.id()))
+            .map(|planned| {
+                Entry::not_run(
+                    planned.id().clone(),
+                    planned.job().package().clone(),
+                    planned.job().channel().clone(),
+                    planned.job().feature_set().clone(),
+                    planned.command().clone(),
+                )
+            })
+            .collect();
+    }
+
+    println!(
+        "{} {} job(s) from {} report(s)",
+        Paint::cyan("      Merged").bold(),
+        merged.len(),
+        merge_args.reports().len()
+    );
+    print_jobs("   Duplicate", &duplicates);
+    print_jobs("  Unexpected", &unexpected);
+    print_jobs("     Missing", &missing);
+
+    // Jobs no shard ran show up in the merged report as not run
+    let missing_count = missing.len();
+    merged.extend(missing);
+    let report = Report::from_entries(merged);
+    report.write(matrix_args.report())?;
+
+    let (passed, failed) = report.totals();
+    let unfinished = report.jobs().len() - passed - failed - missing_count;
+    println!(
+        "{} {passed} passed, {failed} failed",
+        Paint::cyan("     Results").bold()
+    );
+
+    let mut problems = Vec::new();
+    if failed > 0 {
+        problems.push(format!("{failed} job(s) failed"));
+    }
+    if unfinished > 0 {
+        problems.push(format!("{unfinished} job(s) did not finish"));
+    }
+    if missing_count > 0 {
+        problems.push(format!("{missing_count} job(s) of the plan are missing"));
+    }
+    if !unexpected.is_empty() {
+        problems.push(format!("{} job(s) are not in the plan", unexpected.len()));
+    }
+    if !duplicates.is_empty() {
+        problems.push(format!(
+            "{} job(s) were reported more than once",
+            duplicates.len()
+        ));
+    }
+    if problems.is_empty() {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", problems.join(", ")))
+    }
+}
+
+/// The first result of every job in the reports, and the jobs more than one of them
+/// holds a result for. A later result is dropped even if it disagrees with the first.
+fn merge(reports: &[Report]) -> (Vec<Entry>, Vec<Entry>) {
+    let mut merged: Vec<Entry> = Vec::new();
+    let mut seen: HashMap<&String, usize> = HashMap::new();
+    for entry in reports.iter().flat_map(Report::jobs) {
+        let count = seen.entry(entry.id()).or_default();
+        *count += 1;
+        if *count == 1 {
+            merged.push(entry.clone());
+        }
+    }
+    let duplicates = merged
+        .iter()
+        .filter(|entry| seen[entry.id()] > 1)
+        .cloned()
+        .collect();
+    (merged, duplicates)
+}
+
+fn print_jobs(label: &str, entries: &[Entry]) {
+    for entry in entries {
+        println!(
+            "{} {} {} [{}] ({})",
+            Paint::yellow(label).bold(),
+            entry.id(),
+            entry.package(),
+            entry.features(),
+            entry.channel()
+        );
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::merge;
+    use crate::runtime::{
+        execute::TaskResult,
+        report::{Entry, Report},
+    };
+
+    fn ids(entries: &[Entry]) -> Vec<(&str, &str)> {
+        entries
+            .iter()
+            .map(|entry| (entry.id().as_str(), entry.result().label()))
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_first_result_of_a_job_reported_twice() {
+        let first = Report::from_entries(vec![
+            Entry::of(&["a"], TaskResult::Success, 1),
+            Entry::of(&["b"], TaskResult::Fail(101), 1),
+        ]);
+        // The other shard ran `b` as well, and it passed there
+        let second = Report::from_entries(vec![
+            Entry::of(&["b"], TaskResult::Success, 1),
+            Entry::of(&["c"], TaskResult::Success, 1),
+        ]);
+
+        let (merged, duplicates) = merge(&[first, second]);
+        assert_eq!(ids(&merged), [("a", "OK"), ("b", "FAILED"), ("c", "OK")]);
+        assert_eq!(ids(&duplicates), [("b", "FAILED")]);
+    }
+}
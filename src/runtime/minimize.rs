@@ -0,0 +1,207 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::{Feature, FeatureMatrix, FeatureSet},
+    runtime::{
+        diagnostic::Diagnostic,
+        execute::{Job, JobReport, TaskResult},
+        generate_config, get_workspace_members,
+        pool::Pool,
+        process,
+    },
+};
+use anyhow::{anyhow, Result};
+use cargo_metadata::Metadata;
+use yansi::Paint;
+
+/// What a subset of a failing set has to keep to count
+struct Target<'a> {
+    job: &'a Job,
+    /// Where the failing set is in the plan, which the logs of its subsets go by
+    index: usize,
+    /// The `always_include` features of the channel, which no subset may leave out
+    fixed: FeatureSet,
+    /// The `skip` sets of the channel, which are never run
+    skip: FeatureMatrix,
+    /// The first error of the failing set, which a subset has to fail with as well
+    error: Option<&'a Diagnostic>,
+}
+
+/// Shrink every failing feature set to one that still fails, with the same first error
+/// if cargo's messages were read, but does not if any of its features is left out. Only
+/// sets the channel allows are tried. The largest sets go first, and a set holding one
+/// already found for the same error is not shrunk again. The reports are in plan order.
+pub(crate) fn run(pool: &Pool, reports: &[JobReport], metadata: &Metadata) -> Result<()> {
+    let mut failed: Vec<(usize, &JobReport)> = reports
+        .iter()
+        .enumerate()
+        .filter(|(_, report)| report.result().is_failure())
+        .collect();
+    failed.sort_by_key(|(_, report)| std::cmp::Reverse(report.job().feature_set().len()));
+
+    // Each set found, with the failing set it was found from and how many more hold it
+    let mut minimized: Vec<(Job, &JobReport, usize)> = Vec::new();
+    for (index, report) in failed {
+        let job = report.job();
+        let explained = minimized.iter_mut().find(|(found, from, _)| {
+            found.package() == job.package()
+                && found.channel() == job.channel()
+                && from.error() == report.error()
+                && found.feature_set().is_subset(job.feature_set())
+        });
+        if let Some((_, _, others)) = explained {
+            *others += 1;
+            continue;
+        }
+        let target = target(metadata, index, report)?;
+        let feature_set = minimize(pool, &target)?;
+        // What an interrupted search found proves nothing
+        if process::interrupted() {
+            break;
+        }
+        minimized.push((job.with_feature_set(feature_set), report, 0));
+    }
+
+    for (found, report, others) in &minimized {
+        let job = report.job();
+        print!("{} {} ", Paint::cyan("   Minimized").bold(), job.package());
+        if found.feature_set() == job.feature_set() {
+            print!(
+                "[{}] fails, and no smaller set of it fails the same way",
+                job.feature_set()
+            );
+        } else {
+            print!(
+                "[{}] fails with just [{}]",
+                job.feature_set(),
+                found.feature_set()
+            );
+        }
+        if *others > 0 {
+            print!(", as do the {others} other failing set(s) holding it");
+        }
+        println!();
+        println!("{}", found.command_line());
+    }
+    if !minimized.is_empty() {
+        println!();
+    }
+    Ok(())
+}
+
+fn target<'a>(metadata: &Metadata, index: usize, report: &'a JobReport) -> Result<Target<'a>> {
+    let job = report.job();
+    let package = get_workspace_members(metadata)
+        .find(|package| package.name == *job.package())
+        .ok_or_else(|| anyhow!("{} is not a member of the workspace", job.package()))?;
+    let (_, config) = generate_config(package)?;
+    Ok(Target {
+        job,
+        index,
+        fixed: config.always_include(job.channel())?,
+        skip: config.skip(job.channel())?,
+        error: report.error().as_ref(),
+    })
+}
+
+/// Delta debugging: try the chunks of the features left, then what is left without each
+/// chunk, and split finer whenever none of them fails
+fn minimize(pool: &Pool, target: &Target<'_>) -> Result<FeatureSet> {
+    let (job, fixed) = (target.job, &target.fixed);
+    let with =
+        |features: &[Feature]| -> FeatureSet { fixed.iter().chain(features).cloned().collect() };
+    let mut features: Vec<Feature> = job
+        .feature_set()
+        .iter()
+        .filter(|feature| !fixed.contains(*feature))
+        .cloned()
+        .collect();
+    println!(
+        "{} {} [{}], {} feature(s) to leave out",
+        Paint::cyan("    Minimize").bold(),
+        job.package(),
+        job.feature_set(),
+        features.len()
+    );
+    println!();
+
+    // The features that cannot be left out may fail on their own
+    if !features.is_empty() && first_failure(pool, target, &[with(&[])])?.is_some() {
+        return Ok(with(&[]));
+    }
+
+    let mut granularity = 2;
+    while features.len() >= 2 {
+        let size = features.len().div_ceil(granularity);
+        let chunks: Vec<Vec<Feature>> = features.chunks(size).map(<[Feature]>::to_vec).collect();
+        let mut candidates = chunks.clone();
+        // With two chunks, leaving one out is trying the other
+        if chunks.len() > 2 {
+            for index in 0..chunks.len() {
+                candidates.push(
+                    chunks
+                        .iter()
+                        .enumerate()
+                        .filter(|(other, _)| *other != index)
+                        .flat_map(|(_, chunk)| chunk.iter().cloned())
+                        .collect(),
+                );
+            }
+        }
+        let sets: Vec<FeatureSet> = candidates.iter().map(|c| with(c)).collect();
+        match first_failure(pool, target, &sets)? {
+            Some(index) if index < chunks.len() => {
+                features = candidates.swap_remove(index);
+                granularity = 2;
+            }
+            Some(index) => {
+                features = candidates.swap_remove(index);
+                granularity = (chunks.len() - 1).max(2);
+            }
+            None if chunks.len() >= features.len() => break,
+            None => granularity = (granularity * 2).min(features.len()),
+        }
+    }
+    Ok(with(&features))
+}
+
+/// Run the job with each feature set the channel allows, and find the first that fails
+/// the way the original set did
+fn first_failure(pool: &Pool, target: &Target<'_>, sets: &[FeatureSet]) -> Result<Option<usize>> {
+    let allowed: Vec<usize> = (0..sets.len())
+        .filter(|index| !target.skip.contains(&sets[*index]))
+        .collect();
+    let jobs: Vec<(usize, Job)> = allowed
+        .iter()
+        .map(|index| {
+            let job = target.job.with_feature_set(sets[*index].clone());
+            (target.index, job)
+        })
+        .collect();
+    // The pool stops at the first failure, so after one with another error the sets
+    // it did not get to are run again
+    let mut start = 0;
+    while start < jobs.len() && !process::interrupted() {
+        let reports = pool.run(&jobs[start..])?;
+        if let Some(position) = reports.iter().position(|report| {
+            report.result().is_failure()
+                && (target.error.is_none() || report.error().as_ref() == target.error)
+        }) {
+            return Ok(Some(allowed[start + position]));
+        }
+        match reports
+            .iter()
+            .position(|report| matches!(report.result(), TaskResult::NotRun))
+        {
+            Some(position) if position > 0 => start += position,
+            _ => break,
+        }
+    }
+    Ok(None)
+}
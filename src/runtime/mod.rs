@@ -6,14 +6,46 @@
 // option. All files in the project carrying such notice may not be copied,
 // modified, or distributed except according to those terms.
 
+mod cache;
+mod ci;
 mod cli;
+mod diagnostic;
+mod events;
 mod execute;
+mod explain;
+mod graph;
+mod list;
+mod logs;
+mod merge;
+mod minimize;
+mod nextest;
+mod plan;
+mod pool;
+mod process;
+mod report;
+mod shard;
+mod state;
+mod summary;
+mod template;
 
-use self::cli::{Cargo, CargoSubcommands};
+use self::cli::{
+    CacheArgs, CacheCommand, Cargo, CargoSubcommands, MatrixArgs, MessageFormat, VarArgs,
+};
 use crate::{
     config::Config,
-    feature::FeatureMatrix,
-    runtime::execute::{Task, TaskKind, TaskResult},
+    feature::{FeatureMatrix, FeatureSet},
+    runtime::{
+        cache::Cache,
+        ci::Ci,
+        events::{Event, Planned},
+        execute::{Custom, Job, JobReport, Policy, Task, TaskKind, TaskResult},
+        logs::LogDir,
+        nextest::Nextest,
+        pool::Pool,
+        report::Report,
+        shard::{Shard, Weights},
+        state::StateFile,
+    },
 };
 use anyhow::{anyhow, Result};
 use cargo_metadata::{Metadata, MetadataCommand, Package};
@@ -22,7 +54,13 @@ use figment::{
     providers::{Format, Json},
     Figment,
 };
-use std::{ffi::OsString, path::PathBuf};
+use regex::Regex;
+use std::{
+    ffi::OsString,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
 use yansi::Paint;
 
 pub(crate) fn run<I, T>(args: Option<I>) -> Result<()>
@@ -39,100 +77,477 @@ where
 
     match cli {
         Cargo::Matrix(matrix_args) => {
-            // Grab the manifest path from the command line, if supplied
-            let manifest_path = matrix_args.manifest_path();
-            // Read the cargo metadata
-            let metadata = load_metadata(manifest_path)?;
-            // Determine the channel, default is 'default'
-            let channel = matrix_args.channel().as_deref().unwrap_or("default");
-            // Generate the feature set matricies for every package in the workspace
-            let matricies: Vec<(&Package, FeatureMatrix)> = get_workspace_members(&metadata)
-                .map(generate_config)
-                .filter_map(Result::ok)
-                .map(|(package, config)| generate_matrix(package, &config, channel))
-                .filter_map(Result::ok)
-                .collect();
-            // Output some stuff
-            println!();
-            println!(
-                "{} Using channel config '{channel}'",
-                Paint::cyan("     Channel").bold()
-            );
-            println!();
-
-            // Determine the base command we are running
-            let (task_kind, varargs) = match matrix_args.command() {
-                CargoSubcommands::Build(varargs) => (TaskKind::Build, varargs),
-                CargoSubcommands::Check(varargs) => (TaskKind::Check, varargs),
-                CargoSubcommands::Clippy(varargs) => (TaskKind::Clippy, varargs),
-                CargoSubcommands::LlvmCov(varargs) => (TaskKind::LlvmCov, varargs),
-                CargoSubcommands::Test(varargs) => (TaskKind::Test, varargs),
-            };
-
-            // Filter the matricies if a specific package was specified at the command line
-            let matricies = if let Some(package) = matrix_args.package() {
-                matricies
-                    .iter()
-                    .filter(|(pkg, _)| pkg.name == *package)
-                    .cloned()
-                    .collect()
-            } else {
-                let num_chunks = matrix_args.num_chunks();
-                let chunk = matrix_args.chunk();
-                if *chunk == 0 {
-                    return Err(anyhow!("chunk argument cannot be 0"));
-                }
-                if *num_chunks == 0 {
-                    return Err(anyhow!("num_chunks argument cannot be 0"));
+            if *matrix_args.message_format() == MessageFormat::Json {
+                events::enable()?;
+            }
+            // Plans and reports hold everything the metadata would have told us
+            match matrix_args.command() {
+                CargoSubcommands::Apply(apply_args) => {
+                    return plan::apply(&matrix_args, apply_args)
                 }
-                if chunk > num_chunks {
-                    return Err(anyhow!("chunk must be less than or equal to num_chunks"));
+                CargoSubcommands::MergeReports(merge_args) => {
+                    return merge::run(&matrix_args, merge_args)
                 }
+                _ => {}
+            }
+            // Read the cargo metadata
+            let metadata = load_metadata(matrix_args.manifest_path())?;
 
-                let mut chunk_size = matricies.len() / num_chunks;
-                if matricies.len() % num_chunks != 0 {
-                    chunk_size += 1;
+            match matrix_args.command() {
+                CargoSubcommands::Cache(cache_args) => run_cache(&metadata, cache_args),
+                CargoSubcommands::Plan(plan_args) => plan::run(&matrix_args, &metadata, plan_args),
+                CargoSubcommands::List(list_args) => list::run(&matrix_args, &metadata, list_args),
+                CargoSubcommands::Explain(explain_args) => {
+                    explain::run(&matrix_args, &metadata, explain_args)
                 }
-                let Some(matrix_chunk) = matricies.chunks(chunk_size).nth(chunk - 1) else {
-                    println!(
-                        "Chunk is empty (did you ask for more chunks than there are packages?"
-                    );
-                    return Ok(());
-                };
-                if *num_chunks != 1 {
-                    let len = matrix_chunk.len();
-                    let packages: String = matrix_chunk
-                        .iter()
-                        .flat_map(|(p, _)| [&p.name, ","])
-                        .collect();
-                    let packages = packages.trim_end_matches(',');
-                    print!("{}", Paint::cyan("    Chunking ").bold());
-                    println!(
-                        "Running on chunk {chunk} out of {num_chunks} ({len} package(s): {packages})"
-                    );
+                CargoSubcommands::Graph(graph_args) => {
+                    graph::run(&matrix_args, &metadata, graph_args)
                 }
-                matrix_chunk.to_vec()
-            };
-
-            // Execute the task against the matricies
-            for (package, matrix) in matricies {
-                let task_result = Task::new(
-                    task_kind,
-                    package.name.clone(),
-                    matrix,
-                    manifest_path.clone(),
-                    varargs.args().clone(),
-                    *matrix_args.dry_run(),
-                )
-                .execute()?;
-                match task_result {
-                    TaskResult::Success => continue,
-                    TaskResult::Fail(code) => return Err(anyhow!("task failed: {}", code)),
+                command => {
+                    let (task_kind, varargs) = task(&metadata, command)
+                        .ok_or_else(|| anyhow!("the subcommand does not run jobs"))?;
+                    run_matrix(&matrix_args, &metadata, &task_kind, varargs)
                 }
             }
         }
     }
+}
+
+/// The kind of job a subcommand runs, and the arguments it passes on
+fn task<'a>(metadata: &Metadata, command: &'a CargoSubcommands) -> Option<(TaskKind, &'a VarArgs)> {
+    Some(match command {
+        CargoSubcommands::Build(varargs) => (TaskKind::Build, varargs),
+        CargoSubcommands::Check(varargs) => (TaskKind::Check, varargs),
+        CargoSubcommands::Clippy(varargs) => (TaskKind::Clippy, varargs),
+        CargoSubcommands::LlvmCov(varargs) => (TaskKind::LlvmCov, varargs),
+        CargoSubcommands::Test(test_args) => (TaskKind::Test, test_args.varargs()),
+        CargoSubcommands::Run(run_args) => (
+            TaskKind::Custom(Custom::new(run_args.verb().clone())),
+            run_args.varargs(),
+        ),
+        CargoSubcommands::Exec(varargs) => (TaskKind::Exec, varargs),
+        CargoSubcommands::Nextest(nextest_args) => (
+            TaskKind::Nextest(Nextest::new(
+                metadata.target_directory.as_std_path(),
+                metadata.workspace_root.as_std_path(),
+                nextest_args.partition().clone(),
+            )),
+            nextest_args.varargs(),
+        ),
+        CargoSubcommands::Cache(_)
+        | CargoSubcommands::Plan(_)
+        | CargoSubcommands::Apply(_)
+        | CargoSubcommands::List(_)
+        | CargoSubcommands::Explain(_)
+        | CargoSubcommands::Graph(_)
+        | CargoSubcommands::MergeReports(_) => return None,
+    })
+}
+
+/// Run the task against the feature set matrix of every selected package
+fn run_matrix(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    task_kind: &TaskKind,
+    varargs: &VarArgs,
+) -> Result<()> {
+    let Some(jobs) = resolve_jobs(matrix_args, metadata, task_kind, varargs, &mut io::stdout())?
+    else {
+        return Ok(());
+    };
+    let jobs = match matrix_args.shard() {
+        Some(shard) => select_shard(jobs, *shard, matrix_args.shard_durations().as_deref())?,
+        None => jobs,
+    };
+    execute(
+        matrix_args,
+        matrix_args.command(),
+        jobs,
+        metadata.target_directory.as_std_path(),
+        Some(metadata),
+    )
+}
+
+/// Expand the feature set matrix of every selected package into its jobs, writing what
+/// was selected to `out`. There are no jobs when the chunk asked for is empty.
+fn resolve_jobs(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    task_kind: &TaskKind,
+    varargs: &VarArgs,
+    out: &mut dyn Write,
+) -> Result<Option<Vec<Job>>> {
+    // Grab the manifest path from the command line, if supplied
+    let manifest_path = matrix_args.manifest_path();
+    // Determine the channel, default is 'default'
+    let channel = matrix_args.channel().as_deref().unwrap_or("default");
+    // Generate the feature set matricies for every package in the workspace
+    let configs: Vec<(&Package, Config)> = get_workspace_members(metadata)
+        .map(generate_config)
+        .filter_map(Result::ok)
+        .collect();
+    let matricies: Vec<(&Package, &Config, FeatureMatrix)> = configs
+        .iter()
+        .map(|(package, config)| {
+            generate_matrix(package, config, channel)
+                .map(|(package, matrix)| (package, config, matrix))
+        })
+        .filter_map(Result::ok)
+        .collect();
+    // Output some stuff
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{} Using channel config '{channel}'",
+        Paint::cyan("     Channel").bold()
+    )?;
+    writeln!(out)?;
+
+    let num_chunks = matrix_args.num_chunks();
+    let chunk = matrix_args.chunk();
+    if *chunk == 0 {
+        return Err(anyhow!("chunk argument cannot be 0"));
+    }
+    if *num_chunks == 0 {
+        return Err(anyhow!("num_chunks argument cannot be 0"));
+    }
+    if chunk > num_chunks {
+        return Err(anyhow!("chunk must be less than or equal to num_chunks"));
+    }
+    if matrix_args.shard().is_some() && *num_chunks != 1 {
+        return Err(anyhow!("shard cannot be used with num_chunks"));
+    }
+
+    // Filter the matricies if a specific package was specified at the command line,
+    // chunking its feature sets if asked to
+    let matricies = if let Some(package) = matrix_args.package() {
+        let mut selected: Vec<(&Package, &Config, FeatureMatrix)> = matricies
+            .iter()
+            .filter(|(pkg, _, _)| pkg.name == *package)
+            .cloned()
+            .collect();
+        if *matrix_args.chunk_features() {
+            for (pkg, _, matrix) in &mut selected {
+                let total = matrix.len();
+                let sets: Vec<FeatureSet> = matrix.iter().cloned().collect();
+                *matrix = chunk_of(&sets, *num_chunks, *chunk)
+                    .iter()
+                    .cloned()
+                    .collect();
+                writeln!(
+                    out,
+                    "{} Running on chunk {chunk} out of {num_chunks} ({} of {total} feature \
+                     set(s) of {})",
+                    Paint::cyan("    Chunking").bold(),
+                    matrix.len(),
+                    pkg.name
+                )?;
+            }
+        }
+        selected
+    } else {
+        let matrix_chunk = chunk_of(&matricies, *num_chunks, *chunk);
+        if matrix_chunk.is_empty() {
+            writeln!(
+                out,
+                "Chunk is empty (did you ask for more chunks than there are packages?"
+            )?;
+            return Ok(None);
+        }
+        if *num_chunks != 1 {
+            let len = matrix_chunk.len();
+            let packages: String = matrix_chunk
+                .iter()
+                .flat_map(|(p, _, _)| [&p.name, ","])
+                .collect();
+            let packages = packages.trim_end_matches(',');
+            writeln!(
+                out,
+                "{} Running on chunk {chunk} out of {num_chunks} ({len} package(s): {packages})",
+                Paint::cyan("    Chunking").bold()
+            )?;
+        }
+        matrix_chunk.to_vec()
+    };
+
+    if matches!(task_kind, TaskKind::Exec) && varargs.args().is_empty() {
+        return Err(anyhow!("exec requires a command to run"));
+    }
+
+    // Expand the matricies into one job per feature set
+    let mut jobs = Vec::new();
+    for (package, config, matrix) in matricies {
+        // Programs other than cargo are pointed at the package's own manifest
+        let manifest_path = match *task_kind {
+            TaskKind::Exec => Some(package.manifest_path.clone().into()),
+            _ => manifest_path.clone(),
+        };
+        jobs.extend(
+            Task::new(
+                task_kind.for_package(config, varargs.args()),
+                package.name.clone(),
+                channel.to_string(),
+                matrix,
+                manifest_path,
+                varargs.args().clone(),
+                generate_policy(matrix_args, config, channel)?,
+                *matrix_args.dry_run(),
+            )
+            .jobs(),
+        );
+    }
 
+    Ok(Some(jobs))
+}
+
+/// The `chunk`th of `num_chunks` chunks of the items, counting from 1. Every chunk but
+/// the last has the same size.
+pub(crate) fn chunk_of<T>(items: &[T], num_chunks: usize, chunk: usize) -> &[T] {
+    let size = items.len().div_ceil(num_chunks).max(1);
+    items.chunks(size).nth(chunk - 1).unwrap_or_default()
+}
+
+/// Keep only this machine's share of the jobs
+fn select_shard(jobs: Vec<Job>, shard: Shard, durations: Option<&Path>) -> Result<Vec<Job>> {
+    let weights = match durations {
+        Some(path) => Weights::from_report(path)?,
+        None => Weights::Count,
+    };
+    let total = jobs.len();
+    let jobs = shard.select(jobs, &weights);
+    print!("{}", Paint::cyan("    Sharding ").bold());
+    println!("Running shard {shard} ({} of {total} job(s))", jobs.len());
+    Ok(jobs)
+}
+
+/// Run the jobs and report on them, `command` deciding how they are run
+fn execute(
+    matrix_args: &MatrixArgs,
+    command: &CargoSubcommands,
+    jobs: Vec<Job>,
+    target_directory: &Path,
+    metadata: Option<&Metadata>,
+) -> Result<()> {
+    let workers = *matrix_args.jobs();
+    if workers == 0 {
+        return Err(anyhow!("jobs argument cannot be 0"));
+    }
+    // Without keep going, the first failure ends the run
+    let keep_going = *matrix_args.keep_going();
+    // cargo's messages go in the event stream, give the summary the first error of each
+    // failing job, and tell minimizing whether a subset fails the same way
+    let messages = events::enabled() || keep_going || *matrix_args.minimize();
+    let jobs: Vec<Job> = jobs
+        .into_iter()
+        .map(|mut job| {
+            let _ = job.set_dry_run(*matrix_args.dry_run());
+            let _ = job.set_messages(messages);
+            job
+        })
+        .collect();
+
+    events::emit(&Event::PlanComputed {
+        jobs: jobs.iter().map(Planned::from).collect(),
+    })?;
+
+    let max_failures = if keep_going {
+        matrix_args.max_failures().unwrap_or(usize::MAX)
+    } else {
+        1
+    };
+    if max_failures == 0 {
+        return Err(anyhow!("max_failures argument cannot be 0"));
+    }
+
+    // Execute the jobs, each worker building into its own target directory
+    process::handle_interrupts()?;
+    let target_dir = target_directory.join("cargo-matrix");
+    let log_dir = matrix_args
+        .log_dir()
+        .as_deref()
+        .map(LogDir::create)
+        .transpose()?;
+    let state = matrix_args
+        .state()
+        .as_deref()
+        .map(|path| StateFile::open(path, *matrix_args.resume()))
+        .transpose()?;
+    let cache = if *matrix_args.cache() {
+        let metadata = metadata.ok_or_else(|| anyhow!("--cache needs the cargo metadata"))?;
+        let packages = jobs.iter().map(|job| job.package().as_str());
+        let outputs: Vec<PathBuf> = matrix_args
+            .log_dir()
+            .iter()
+            .chain(matrix_args.state())
+            .chain(matrix_args.report().iter().map(|spec| spec.path()))
+            .cloned()
+            .collect();
+        Some(Cache::open(
+            target_dir.join("cache"),
+            metadata,
+            packages,
+            &outputs,
+        )?)
+    } else {
+        None
+    };
+    let mut pool = Pool::new(
+        workers,
+        target_dir.clone(),
+        max_failures,
+        log_dir.clone(),
+        state,
+        cache,
+    );
+    let planned: Vec<(usize, Job)> = jobs.iter().cloned().enumerate().collect();
+    let reports = match command {
+        CargoSubcommands::Test(test_args) if *test_args.two_phase() => {
+            run_two_phase(&mut pool, &planned, max_failures)?
+        }
+        _ => pool.run(&planned)?,
+    };
+    if let Some(log_dir) = &log_dir {
+        log_dir.write_index(&reports)?;
+    }
+    if let Some(ci) = Ci::detect() {
+        ci.write_summary(&reports)?;
+    }
+    let report = Report::new(&reports);
+    if !matrix_args.report().is_empty() {
+        report.write(matrix_args.report())?;
+    }
+    if let (Some(TaskKind::Nextest(nextest)), CargoSubcommands::Nextest(nextest_args)) =
+        (jobs.first().map(Job::kind), command)
+    {
+        if let Some(junit) = nextest_args.junit() {
+            if !*matrix_args.dry_run() {
+                let merged = nextest.merge_junit(&reports, junit)?;
+                println!(
+                    "{} Merged {merged} report(s) into {}",
+                    Paint::cyan("       JUnit").bold(),
+                    junit.display()
+                );
+            }
+        }
+    }
+    if *matrix_args.minimize() && !process::interrupted() {
+        let metadata = metadata.ok_or_else(|| anyhow!("--minimize needs the cargo metadata"))?;
+        // Logs, state and cache are about the jobs of the run, not the subsets tried
+        let pool = Pool::new(workers, target_dir, 1, None, None, None);
+        minimize::run(&pool, &reports, metadata)?;
+    }
+    let failures: Vec<TaskResult> = reports
+        .iter()
+        .map(|report| *report.result())
+        .filter(|result| result.is_failure())
+        .collect();
+    events::emit(&Event::RunFinished {
+        jobs: reports.len(),
+        passed: reports.iter().filter(|r| r.result().is_pass()).count(),
+        failed: failures.len(),
+        interrupted: process::interrupted(),
+        success: failures.is_empty() && !process::interrupted(),
+    })?;
+
+    if process::interrupted() {
+        summary::write(&mut io::stdout().lock(), &reports, report.culprits())?;
+        return Err(anyhow!("interrupted"));
+    } else if keep_going {
+        summary::write(&mut io::stdout().lock(), &reports, report.culprits())?;
+        if !failures.is_empty() {
+            return Err(anyhow!(
+                "{} of {} job(s) failed",
+                failures.len(),
+                jobs.len()
+            ));
+        }
+    } else if let Some(result) = failures.first() {
+        return Err(match result.code() {
+            Some(code) => anyhow!("task failed: {}", code),
+            None => anyhow!("task failed: {}", result.label().to_lowercase()),
+        });
+    }
+
+    Ok(())
+}
+
+/// Compile the tests of every feature set before running any of them, so a set that does
+/// not build can be told apart from one whose tests fail
+fn run_two_phase(
+    pool: &mut Pool,
+    jobs: &[(usize, Job)],
+    max_failures: usize,
+) -> Result<Vec<JobReport>> {
+    let compile_jobs: Vec<(usize, Job)> = jobs
+        .iter()
+        .map(|(index, job)| (*index, job.compile_tests()))
+        .collect();
+    print!("{}", Paint::cyan("       Phase ").bold());
+    println!(
+        "1 of 2, compiling the tests of {} feature set(s)",
+        compile_jobs.len()
+    );
+    println!();
+    let compiled = pool.run(&compile_jobs)?;
+
+    let compile_failures = compiled
+        .iter()
+        .filter(|report| report.result().is_failure())
+        .count();
+    let test_jobs: Vec<(usize, Job)> = jobs
+        .iter()
+        .zip(&compiled)
+        .filter(|(_, report)| report.result().is_pass())
+        .map(|(planned, _)| planned.clone())
+        .collect();
+    let tested = if compile_failures >= max_failures || process::interrupted() {
+        test_jobs
+            .iter()
+            .map(|(_, job)| JobReport::skipped(job, TaskResult::NotRun))
+            .collect()
+    } else {
+        print!("{}", Paint::cyan("       Phase ").bold());
+        println!(
+            "2 of 2, testing {} of {} feature set(s)",
+            test_jobs.len(),
+            jobs.len()
+        );
+        println!();
+        pool.set_max_failures(max_failures - compile_failures);
+        pool.run(&test_jobs)?
+    };
+
+    // The sets that compiled are reported by their test run, the others by their build
+    let mut tested = tested.into_iter();
+    compiled
+        .into_iter()
+        .map(|report| {
+            if report.result().is_pass() {
+                tested
+                    .next()
+                    .ok_or_else(|| anyhow!("missing test report for a compiled feature set"))
+            } else {
+                Ok(report)
+            }
+        })
+        .collect()
+}
+
+/// Manage the result cache kept under the target directory
+fn run_cache(metadata: &Metadata, cache_args: &CacheArgs) -> Result<()> {
+    let cache_dir = metadata.target_directory.join("cargo-matrix").join("cache");
+    match cache_args.command() {
+        CacheCommand::Prune(prune_args) => {
+            let older_than = prune_args
+                .older_than()
+                .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+            let removed = cache::prune(cache_dir.as_std_path(), older_than)?;
+            println!(
+                "{} Removed {removed} cached result(s) from {cache_dir}",
+                Paint::cyan("     Pruning").bold()
+            );
+        }
+    }
     Ok(())
 }
 
@@ -152,7 +567,7 @@ fn load_metadata(manifest_path: &Option<PathBuf>) -> Result<Metadata> {
     Ok(cmd.exec()?)
 }
 
-fn generate_config(package: &Package) -> Result<(&Package, Config)> {
+pub(crate) fn generate_config(package: &Package) -> Result<(&Package, Config)> {
     let figment = if let Some(package_config) = package.metadata.get("cargo-matrix") {
         Figment::from(Config::default())
             .merge(Figment::from(Json::string(&package_config.to_string())))
@@ -169,3 +584,22 @@ fn generate_matrix<'a>(
 ) -> Result<(&'a Package, FeatureMatrix)> {
     Ok((package, FeatureMatrix::new(package, config, channel)?))
 }
+
+/// Combine the command line and the channel config into the limits for each job,
+/// with the command line taking precedence
+fn generate_policy(matrix_args: &MatrixArgs, config: &Config, channel: &str) -> Result<Policy> {
+    let timeout = matrix_args
+        .timeout()
+        .or(config.timeout(channel)?)
+        .map(Duration::from_secs);
+    let retries = matrix_args
+        .retries()
+        .map_or_else(|| config.retries(channel), Ok)?;
+    let retry_on = matrix_args
+        .retry_on()
+        .clone()
+        .map_or_else(|| config.retry_on(channel), |re| Ok(Some(re)))?
+        .map(|re| Regex::new(&re))
+        .transpose()?;
+    Ok(Policy::new(timeout, retries, retry_on))
+}
@@ -0,0 +1,381 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::{
+    execute::{Job, JobReport, TaskResult},
+    logs,
+};
+use anyhow::Result;
+use quick_xml::{
+    events::{BytesDecl, BytesEnd, BytesStart, Event},
+    Reader, Writer,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+};
+
+/// The nextest profile the jobs run under. It inherits from the default profile and only
+/// adds the JUnit report.
+const PROFILE: &str = "cargo-matrix";
+
+/// Flags of `cargo nextest run` that say how the tests are built. The run only reads the
+/// archive, so these go to `cargo nextest archive` instead.
+const BUILD_FLAGS: &[&str] = &[
+    "--all-targets",
+    "--benches",
+    "--bins",
+    "--cargo-quiet",
+    "--cargo-verbose",
+    "--examples",
+    "--frozen",
+    "--ignore-rust-version",
+    "--lib",
+    "--locked",
+    "--offline",
+    "--release",
+    "--tests",
+    "--timings",
+];
+
+/// As `BUILD_FLAGS`, for the options that take a value
+const BUILD_OPTIONS: &[&str] = &[
+    "--bench",
+    "--bin",
+    "--build-jobs",
+    "--cargo-message-format",
+    "--cargo-profile",
+    "--config",
+    "--example",
+    "--target",
+    "--target-dir",
+    "--test",
+    "-Z",
+];
+
+/// Runs the tests of each feature set with nextest.
+///
+/// The tests are built into an archive once per feature set, so retries only run the
+/// tests again. Everything for a job lives in its own directory under
+/// `<target>/cargo-matrix/nextest`.
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub(crate) struct Nextest {
+    /// Both paths are on the machine running the jobs, so a plan leaves them out and
+    /// they are filled in where it is applied
+    #[serde(skip)]
+    dir: PathBuf,
+    #[serde(skip)]
+    workspace_root: PathBuf,
+    partition: Option<String>,
+}
+
+impl Nextest {
+    pub(crate) fn new(
+        target_directory: &Path,
+        workspace_root: &Path,
+        partition: Option<String>,
+    ) -> Self {
+        let mut nextest = Self {
+            dir: PathBuf::new(),
+            workspace_root: PathBuf::new(),
+            partition,
+        };
+        nextest.locate(target_directory, workspace_root);
+        nextest
+    }
+
+    /// Point the jobs at the target directory and workspace of this machine
+    pub(crate) fn locate(&mut self, target_directory: &Path, workspace_root: &Path) {
+        self.dir = target_directory.join("cargo-matrix").join("nextest");
+        self.workspace_root = workspace_root.to_path_buf();
+    }
+
+    /// The partition of the tests each job runs, if any
+    pub(crate) fn partition(&self) -> Option<&str> {
+        self.partition.as_deref()
+    }
+
+    pub(crate) fn archive(&self, job: &Job) -> PathBuf {
+        self.job_dir(job).join("archive.tar.zst")
+    }
+
+    /// The arguments to `cargo nextest run` that run the archive of the job
+    pub(crate) fn run_args(&self, job: &Job) -> Vec<String> {
+        let mut args = vec![
+            "--archive-file".to_string(),
+            format!("{}", self.archive(job).display()),
+            "--workspace-remap".to_string(),
+            format!("{}", self.workspace_root.display()),
+            "--tool-config-file".to_string(),
+            format!("cargo-matrix:{}", self.config(job).display()),
+            "--profile".to_string(),
+            PROFILE.to_string(),
+        ];
+        if let Some(partition) = &self.partition {
+            args.push("--partition".to_string());
+            args.push(partition.clone());
+        }
+        args
+    }
+
+    /// Write the tool config pointing the JUnit report into the job directory, and drop
+    /// the report of an earlier run
+    pub(crate) fn prepare(&self, job: &Job) -> Result<()> {
+        fs::create_dir_all(self.job_dir(job))?;
+        let junit = self.junit(job);
+        if junit.exists() {
+            fs::remove_file(&junit)?;
+        }
+        // An absolute path replaces the store directory nextest would put it under
+        fs::write(
+            self.config(job),
+            format!(
+                "[profile.{PROFILE}.junit]\npath = {:?}\n",
+                junit.display().to_string()
+            ),
+        )?;
+        Ok(())
+    }
+
+    /// Merge the JUnit reports of every job that ran into one, adding the feature set to
+    /// the name of each test suite and test case. Returns the number of reports merged.
+    pub(crate) fn merge_junit(&self, reports: &[JobReport], path: &Path) -> Result<usize> {
+        let mut events = Vec::new();
+        let (mut tests, mut failures, mut errors) = (0, 0, 0);
+        let mut merged = 0;
+
+        for report in reports {
+            // The report of a job that was skipped or stopped is left from an earlier run
+            let ran = matches!(
+                report.result(),
+                TaskResult::Success
+                    | TaskResult::Flaky
+                    | TaskResult::Fail(_)
+                    | TaskResult::CompileError(_)
+                    | TaskResult::Timeout
+            );
+            let junit = self.junit(report.job());
+            if !ran || !junit.exists() {
+                continue;
+            }
+            let xml = fs::read_to_string(&junit)?;
+            let suffix = format!(" [{}]", report.job().feature_set());
+            let mut reader = Reader::from_str(&xml);
+            let _ = reader.trim_text(true);
+            loop {
+                match reader.read_event()? {
+                    Event::Eof => break,
+                    Event::Decl(_) | Event::PI(_) | Event::DocType(_) => {}
+                    Event::Start(e) | Event::Empty(e) if e.name().as_ref() == b"testsuites" => {}
+                    Event::End(e) if e.name().as_ref() == b"testsuites" => {}
+                    Event::Start(e) => {
+                        if e.name().as_ref() == b"testsuite" {
+                            tests += count(&e, b"tests")?;
+                            failures += count(&e, b"failures")?;
+                            errors += count(&e, b"errors")?;
+                        }
+                        events.push(Event::Start(relabel(&e, &suffix)?));
+                    }
+                    Event::Empty(e) => events.push(Event::Empty(relabel(&e, &suffix)?)),
+                    event => events.push(event.into_owned()),
+                }
+            }
+            merged += 1;
+        }
+
+        let mut writer = Writer::new_with_indent(BufWriter::new(File::create(path)?), b' ', 4);
+        writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+        let mut root = BytesStart::new("testsuites");
+        root.push_attribute(("name", PROFILE));
+        root.push_attribute(("tests", tests.to_string().as_str()));
+        root.push_attribute(("failures", failures.to_string().as_str()));
+        root.push_attribute(("errors", errors.to_string().as_str()));
+        writer.write_event(Event::Start(root))?;
+        for event in events {
+            writer.write_event(event)?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("testsuites")))?;
+        Ok(merged)
+    }
+
+    /// The directory of the job. Names of long feature sets are cut short, so the id
+    /// keeps them apart.
+    fn job_dir(&self, job: &Job) -> PathBuf {
+        self.dir
+            .join(format!("{}-{}", logs::job_name(job), job.id()))
+    }
+
+    fn config(&self, job: &Job) -> PathBuf {
+        self.job_dir(job).join("nextest.toml")
+    }
+
+    fn junit(&self, job: &Job) -> PathBuf {
+        self.job_dir(job).join("junit.xml")
+    }
+}
+
+/// Split the arguments given for `cargo nextest run` into the ones that build the
+/// archive and the ones that run it. Everything after `--` is for the run.
+pub(crate) fn split_args(args: &[String]) -> (Vec<String>, Vec<String>) {
+    let (mut build, mut run) = (Vec::new(), Vec::new());
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            run.push(arg.clone());
+            run.extend(args.by_ref().cloned());
+        } else if BUILD_FLAGS.contains(&arg.as_str()) {
+            build.push(arg.clone());
+        } else if BUILD_OPTIONS.contains(&arg.as_str()) {
+            build.push(arg.clone());
+            build.extend(args.next().cloned());
+        } else if BUILD_OPTIONS.iter().any(|option| {
+            arg.strip_prefix(option)
+                .is_some_and(|value| value.starts_with('=') || *option == "-Z")
+        }) {
+            build.push(arg.clone());
+        } else {
+            run.push(arg.clone());
+        }
+    }
+    (build, run)
+}
+
+/// A copy of the element, with the feature set appended to the name of suites and cases
+fn relabel(element: &BytesStart<'_>, suffix: &str) -> Result<BytesStart<'static>> {
+    let name = String::from_utf8_lossy(element.name().as_ref()).into_owned();
+    let rename = name == "testsuite" || name == "testcase";
+    let mut relabeled = BytesStart::new(name);
+    for attribute in element.attributes() {
+        let attribute = attribute?;
+        if rename && attribute.key.as_ref() == b"name" {
+            let value = format!("{}{suffix}", attribute.unescape_value()?);
+            relabeled.push_attribute(("name", value.as_str()));
+        } else {
+            relabeled.push_attribute(attribute);
+        }
+    }
+    Ok(relabeled)
+}
+
+/// A numeric attribute of a test suite, 0 if it is missing
+fn count(element: &BytesStart<'_>, key: &[u8]) -> Result<usize> {
+    Ok(match element.try_get_attribute(key)? {
+        Some(attribute) => attribute.unescape_value()?.parse()?,
+        None => 0,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split_args, Nextest};
+    use crate::runtime::execute::{Job, JobReport, TaskResult};
+    use std::{env, fs, path::PathBuf};
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    fn nextest(name: &str) -> (PathBuf, Nextest) {
+        let dir = env::temp_dir().join(format!("cargo-matrix-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let nextest = Nextest::new(&dir, &dir, None);
+        (dir, nextest)
+    }
+
+    /// Stand in for nextest writing the report of a job with a single test case
+    fn write_junit(nextest: &Nextest, job: &Job, case: &str) {
+        nextest.prepare(job).unwrap();
+        fs::write(
+            nextest.junit(job),
+            format!(
+                "<testsuites><testsuite name=\"foo\" tests=\"1\" failures=\"0\" errors=\"0\">\
+                 <testcase name=\"{case}\"/></testsuite></testsuites>"
+            ),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn only_jobs_that_ran_are_merged() {
+        let (dir, nextest) = nextest("merge-ran");
+        let passed = Job::check("foo", &["a"]);
+        let cached = Job::check("foo", &["b"]);
+        let not_run = Job::check("foo", &["c"]);
+        for job in [&passed, &cached, &not_run] {
+            write_junit(&nextest, job, "works");
+        }
+        let reports = [
+            JobReport::ran(&passed, TaskResult::Success, None),
+            JobReport::skipped(&cached, TaskResult::Cached),
+            JobReport::skipped(&not_run, TaskResult::NotRun),
+        ];
+
+        let merged = dir.join("junit.xml");
+        assert_eq!(nextest.merge_junit(&reports, &merged).unwrap(), 1);
+        let xml = fs::read_to_string(&merged).unwrap();
+        assert!(xml.contains("tests=\"1\""), "{xml}");
+        assert!(xml.contains("works [a]"), "{xml}");
+        assert!(!xml.contains("works [b]"), "{xml}");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn long_feature_sets_get_their_own_directory() {
+        let (dir, nextest) = nextest("merge-long");
+        let prefix = "x".repeat(250);
+        let one = Job::check("foo", &[&format!("{prefix}1")]);
+        let two = Job::check("foo", &[&format!("{prefix}2")]);
+        assert_ne!(nextest.job_dir(&one), nextest.job_dir(&two));
+        write_junit(&nextest, &one, "one");
+        write_junit(&nextest, &two, "two");
+        let reports = [
+            JobReport::ran(&one, TaskResult::Success, None),
+            JobReport::ran(&two, TaskResult::Fail(100), None),
+        ];
+
+        let merged = dir.join("junit.xml");
+        assert_eq!(nextest.merge_junit(&reports, &merged).unwrap(), 2);
+        let xml = fs::read_to_string(&merged).unwrap();
+        assert!(xml.contains("tests=\"2\""), "{xml}");
+        assert!(xml.contains("\"one ["), "{xml}");
+        assert!(xml.contains("\"two ["), "{xml}");
+        let _ = fs::remove_dir_all(dir);
+    }
+
+    #[test]
+    fn build_options_go_to_the_archive() {
+        let (build, run) = split_args(&args(&[
+            "--release",
+            "--target",
+            "x86_64-unknown-linux-musl",
+            "--cargo-profile=ci",
+            "-Zbuild-std",
+            "--no-fail-fast",
+            "-E",
+            "test(foo)",
+            "--",
+            "--release",
+        ]));
+        assert_eq!(
+            build,
+            [
+                "--release",
+                "--target",
+                "x86_64-unknown-linux-musl",
+                "--cargo-profile=ci",
+                "-Zbuild-std"
+            ]
+        );
+        assert_eq!(
+            run,
+            ["--no-fail-fast", "-E", "test(foo)", "--", "--release"]
+        );
+    }
+}
@@ -0,0 +1,456 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::{
+    cli::{ApplyArgs, CiProvider, MatrixArgs, PlanArgs, PlanCommand},
+    execute,
+    execute::Job,
+    generate_config, generate_matrix, get_workspace_members, load_metadata, resolve_jobs,
+    select_shard, task,
+};
+use anyhow::{anyhow, Context, Result};
+use cargo_metadata::Metadata;
+use clap::Parser;
+use getset::Getters;
+use serde::{Deserialize, Serialize};
+use std::{
+    fs::File,
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
+use yansi::Paint;
+
+/// GitHub refuses a matrix with more jobs than this
+const GITHUB_MAX_JOBS: usize = 256;
+
+/// Version of the plan file layout
+const PLAN_VERSION: u32 = 3;
+
+/// Every job of a run, resolved so that it can be reviewed and run somewhere else. Paths
+/// are relative to the workspace root, and the target directory is the one of the
+/// workspace the plan is applied in.
+#[derive(Debug, Deserialize, Getters, Serialize)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct JobPlan {
+    version: u32,
+    /// The cargo matrix subcommand the jobs run, followed by its arguments
+    command: Vec<String>,
+    jobs: Vec<PlannedJob>,
+}
+
+#[derive(Debug, Deserialize, Getters, Serialize)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct PlannedJob {
+    id: String,
+    #[serde(flatten)]
+    job: Job,
+    package_manifest: PathBuf,
+    /// What the job runs, for whoever reads the plan
+    command: String,
+}
+
+/// One CI job, running a chunk of the feature sets of a package on a channel
+#[derive(Debug, Serialize)]
+struct Shard {
+    name: String,
+    package: String,
+    channel: String,
+    chunk: usize,
+    num_chunks: usize,
+    jobs: usize,
+    /// The arguments to `cargo matrix` that select this shard
+    args: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    command: Option<String>,
+}
+
+#[derive(Serialize)]
+struct GitHubMatrix<'a> {
+    include: &'a [Shard],
+}
+
+/// Write the plan asked for, the jobs themselves or how to split them across CI jobs
+pub(crate) fn run(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    plan_args: &PlanArgs,
+) -> Result<()> {
+    match plan_args.ci() {
+        Some(ci) => write_ci(matrix_args, metadata, plan_args, *ci),
+        None => write_jobs(matrix_args, metadata, plan_args),
+    }
+}
+
+/// Read a plan written by `plan -o`
+pub(crate) fn read(path: &Path) -> Result<JobPlan> {
+    let file =
+        File::open(path).with_context(|| format!("cannot open the plan {}", path.display()))?;
+    let plan: JobPlan = serde_json::from_reader(BufReader::new(file))
+        .with_context(|| format!("{} is not a plan", path.display()))?;
+    if plan.version != PLAN_VERSION {
+        return Err(anyhow!(
+            "{} is a version {} plan, expected version {PLAN_VERSION}",
+            path.display(),
+            plan.version
+        ));
+    }
+    Ok(plan)
+}
+
+/// Run the jobs of a plan, or the ones picked by id or shard
+pub(crate) fn apply(matrix_args: &MatrixArgs, apply_args: &ApplyArgs) -> Result<()> {
+    let path = apply_args.plan();
+    let plan = read(path)?;
+    let command = PlanCommand::try_parse_from(&plan.command)?;
+
+    let total = plan.jobs.len();
+    if let Some(id) = apply_args
+        .jobs()
+        .iter()
+        .find(|id| !plan.jobs.iter().any(|planned| planned.id == **id))
+    {
+        return Err(anyhow!("there is no job {id} in {}", path.display()));
+    }
+    let metadata = load_metadata(matrix_args.manifest_path())?;
+    let jobs: Vec<Job> = plan
+        .jobs
+        .into_iter()
+        .filter(|planned| apply_args.jobs().is_empty() || apply_args.jobs().contains(&planned.id))
+        .map(|planned| {
+            planned.job.locate(
+                metadata.workspace_root.as_std_path(),
+                metadata.target_directory.as_std_path(),
+            )
+        })
+        .collect();
+    let jobs = match matrix_args.shard() {
+        Some(shard) => select_shard(jobs, *shard, matrix_args.shard_durations().as_deref())?,
+        None => jobs,
+    };
+    println!(
+        "{} Running {} of the {total} job(s) in {}",
+        Paint::cyan("       Apply").bold(),
+        jobs.len(),
+        path.display()
+    );
+
+    execute(
+        matrix_args,
+        command.command(),
+        jobs,
+        metadata.target_directory.as_std_path(),
+        Some(&metadata),
+    )
+}
+
+/// Resolve every job of the matrix and write them out
+fn write_jobs(matrix_args: &MatrixArgs, metadata: &Metadata, plan_args: &PlanArgs) -> Result<()> {
+    if matrix_args.shard().is_some() {
+        return Err(anyhow!(
+            "--shard picks the jobs to apply, not the ones to plan"
+        ));
+    }
+    if plan_args.varargs().args().is_empty() {
+        return Err(anyhow!(
+            "a plan needs the subcommand its jobs run, i.e. `plan -o plan.json -- test`"
+        ));
+    }
+    let command = PlanCommand::try_parse_from(plan_args.varargs().args())?;
+    let (task_kind, varargs) = task(metadata, command.command())
+        .ok_or_else(|| anyhow!("the subcommand of a plan has to run jobs"))?;
+    // The plan may be going to stdout
+    let Some(jobs) = resolve_jobs(
+        matrix_args,
+        metadata,
+        &task_kind,
+        varargs,
+        &mut io::stderr(),
+    )?
+    else {
+        return Ok(());
+    };
+
+    let root = metadata.workspace_root.as_std_path();
+    let jobs = jobs
+        .into_iter()
+        .map(|job| {
+            let package_manifest = get_workspace_members(metadata)
+                .find(|package| package.name == *job.package())
+                .and_then(|package| package.manifest_path.strip_prefix(root).ok())
+                .map(Into::into)
+                .unwrap_or_default();
+            PlannedJob {
+                id: job.id(),
+                command: job.command_line(),
+                package_manifest,
+                job: job.relative_to(root),
+            }
+        })
+        .collect();
+    let plan = JobPlan {
+        version: PLAN_VERSION,
+        command: plan_args.varargs().args().clone(),
+        jobs,
+    };
+
+    let mut out: Box<dyn Write> = match plan_args.output() {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    serde_json::to_writer_pretty(&mut out, &plan)?;
+    writeln!(out)?;
+    out.flush()?;
+    if let Some(path) = plan_args.output() {
+        eprintln!(
+            "{} {} job(s) written to {}",
+            Paint::cyan("        Plan").bold(),
+            plan.jobs.len(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Split the matrix of every package into shards of at most `--jobs-per-shard` feature
+/// sets, and write them out in the form the CI service fans out from
+fn write_ci(
+    matrix_args: &MatrixArgs,
+    metadata: &Metadata,
+    plan_args: &PlanArgs,
+    ci: CiProvider,
+) -> Result<()> {
+    let jobs_per_shard = *plan_args.jobs_per_shard();
+    if jobs_per_shard == 0 {
+        return Err(anyhow!("jobs-per-shard argument cannot be 0"));
+    }
+    let channels = if plan_args.channels().is_empty() {
+        vec![matrix_args
+            .channel()
+            .clone()
+            .unwrap_or_else(|| "default".to_string())]
+    } else {
+        plan_args.channels().clone()
+    };
+    let command = if plan_args.varargs().args().is_empty() {
+        None
+    } else {
+        Some(plan_args.varargs().args().join(" "))
+    };
+
+    let mut shards = Vec::new();
+    for channel in &channels {
+        let packages =
+            get_workspace_members(metadata).filter(|package| match matrix_args.package() {
+                Some(name) => package.name == *name,
+                None => true,
+            });
+        for package in packages {
+            let Ok((package, config)) = generate_config(package) else {
+                continue;
+            };
+            let Ok((_, matrix)) = generate_matrix(package, &config, channel) else {
+                continue;
+            };
+            shards.extend(split(
+                &package.name,
+                channel,
+                matrix.len(),
+                jobs_per_shard,
+                command.as_deref(),
+            ));
+        }
+    }
+    if shards.is_empty() {
+        return Err(anyhow!("there are no feature sets to plan"));
+    }
+
+    match ci {
+        CiProvider::Github if shards.len() > GITHUB_MAX_JOBS => {
+            return Err(anyhow!(
+                "{} shards is more than the {GITHUB_MAX_JOBS} jobs a GitHub matrix allows, raise --jobs-per-shard",
+                shards.len()
+            ));
+        }
+        CiProvider::Gitlab if command.is_none() => {
+            return Err(anyhow!(
+                "a GitLab pipeline needs the command each job runs, i.e. `plan --ci gitlab -- test`"
+            ));
+        }
+        _ => {}
+    }
+
+    let mut out: Box<dyn Write> = match plan_args.output() {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(io::stdout().lock()),
+    };
+    match ci {
+        CiProvider::Github => {
+            serde_json::to_writer(&mut out, &GitHubMatrix { include: &shards })?;
+            writeln!(out)?;
+        }
+        CiProvider::Gitlab => {
+            write_gitlab(&mut out, &shards, plan_args)?;
+        }
+    }
+    out.flush()?;
+
+    if let Some(path) = plan_args.output() {
+        let feature_sets: usize = shards.iter().map(|shard| shard.jobs).sum();
+        eprintln!(
+            "{} {} CI job(s) for {feature_sets} feature set(s) written to {}",
+            Paint::cyan("        Plan").bold(),
+            shards.len(),
+            path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Split the feature sets of a package into as few shards as `jobs_per_shard` allows,
+/// sized the way `--chunk-features` chunks them
+fn split(
+    package: &str,
+    channel: &str,
+    total: usize,
+    jobs_per_shard: usize,
+    command: Option<&str>,
+) -> Vec<Shard> {
+    if total == 0 {
+        return Vec::new();
+    }
+    let chunk_size = total.div_ceil(total.div_ceil(jobs_per_shard));
+    let num_chunks = total.div_ceil(chunk_size);
+    (1..=num_chunks)
+        .map(|chunk| {
+            let (name, args) = if num_chunks == 1 {
+                (
+                    format!("{package} ({channel})"),
+                    format!("--channel {channel} --package {package}"),
+                )
+            } else {
+                (
+                    format!("{package} ({channel}) {chunk}/{num_chunks}"),
+                    format!(
+                        "--channel {channel} --package {package} --chunk-features \
+                         --num-chunks {num_chunks} --chunk {chunk}"
+                    ),
+                )
+            };
+            Shard {
+                name,
+                package: package.to_string(),
+                channel: channel.to_string(),
+                chunk,
+                num_chunks,
+                jobs: chunk_size.min(total - (chunk - 1) * chunk_size),
+                command: command.map(|command| format!("cargo matrix {args} {command}")),
+                args,
+            }
+        })
+        .collect()
+}
+
+/// A child pipeline with a job per shard. Strings are written as JSON, which YAML reads
+/// the same way.
+fn write_gitlab(out: &mut dyn Write, shards: &[Shard], plan_args: &PlanArgs) -> Result<()> {
+    writeln!(out, "# Generated by `cargo matrix plan --ci gitlab`")?;
+    if !plan_args.include().is_empty() {
+        writeln!(out, "include:")?;
+        for include in plan_args.include() {
+            writeln!(out, "  - local: {}", serde_json::to_string(include)?)?;
+        }
+    }
+    for shard in shards {
+        writeln!(out)?;
+        writeln!(out, "{}:", serde_json::to_string(&shard.name)?)?;
+        if let Some(extends) = plan_args.extends() {
+            writeln!(out, "  extends: {}", serde_json::to_string(extends)?)?;
+        }
+        writeln!(out, "  script:")?;
+        if let Some(command) = &shard.command {
+            writeln!(out, "    - {}", serde_json::to_string(command)?)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{split, write_gitlab};
+    use crate::runtime::{
+        chunk_of,
+        cli::{CargoSubcommands, PlanCommand},
+    };
+    use clap::Parser;
+
+    #[test]
+    fn shards_are_the_chunks_of_the_feature_sets() {
+        let shards = split("foo", "default", 10, 4, Some("test"));
+        assert_eq!(
+            shards.iter().map(|shard| shard.jobs).collect::<Vec<_>>(),
+            [4, 4, 2]
+        );
+        assert_eq!(
+            shards[1].args,
+            "--channel default --package foo --chunk-features --num-chunks 3 --chunk 2"
+        );
+        // Each shard runs just as many feature sets as its chunk holds
+        let sets: Vec<usize> = (0..10).collect();
+        for shard in &shards {
+            assert_eq!(
+                chunk_of(&sets, shard.num_chunks, shard.chunk).len(),
+                shard.jobs
+            );
+        }
+
+        let shards = split("foo", "default", 3, 4, None);
+        assert_eq!(shards.len(), 1);
+        assert_eq!(shards[0].args, "--channel default --package foo");
+        assert_eq!(shards[0].command, None);
+        assert!(split("foo", "default", 0, 4, None).is_empty());
+    }
+
+    #[test]
+    fn gitlab_pipeline_has_a_job_per_shard() {
+        let command = PlanCommand::try_parse_from([
+            "plan",
+            "--ci",
+            "gitlab",
+            "--include",
+            "ci/rust.yml",
+            "--extends",
+            ".rust",
+            "--",
+            "test",
+        ])
+        .unwrap();
+        let CargoSubcommands::Plan(plan_args) = command.command() else {
+            panic!("not a plan");
+        };
+        let shards = split("foo", "nightly", 5, 3, Some("test"));
+        let mut out = Vec::new();
+        write_gitlab(&mut out, &shards, plan_args).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"# Generated by `cargo matrix plan --ci gitlab`
+include:
+  - local: "ci/rust.yml"
+
+"foo (nightly) 1/2":
+  extends: ".rust"
+  script:
+    - "cargo matrix --channel nightly --package foo --chunk-features --num-chunks 2 --chunk 1 test"
+
+"foo (nightly) 2/2":
+  extends: ".rust"
+  script:
+    - "cargo matrix --channel nightly --package foo --chunk-features --num-chunks 2 --chunk 2 test"
+"#
+        );
+    }
+}
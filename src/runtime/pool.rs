@@ -0,0 +1,242 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::{
+    cache::Cache,
+    ci::Ci,
+    events::{self, Event},
+    execute::{Console, Job, JobReport, TaskResult},
+    logs::LogDir,
+    process::interrupted,
+    state::StateFile,
+};
+use anyhow::{anyhow, Result};
+use std::{
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    thread,
+};
+use yansi::Paint;
+
+/// Runs jobs across a fixed number of workers.
+///
+/// Each worker builds into its own target directory under `<target>/cargo-matrix`,
+/// which is reused for every job the worker picks up so incremental builds still help.
+pub(crate) struct Pool {
+    workers: usize,
+    target_dir: PathBuf,
+    max_failures: usize,
+    log_dir: Option<LogDir>,
+    state: Option<StateFile>,
+    cache: Option<Cache>,
+    ci: Option<Ci>,
+}
+
+impl Pool {
+    pub(crate) fn new(
+        workers: usize,
+        target_dir: PathBuf,
+        max_failures: usize,
+        log_dir: Option<LogDir>,
+        state: Option<StateFile>,
+        cache: Option<Cache>,
+    ) -> Self {
+        Self {
+            workers,
+            target_dir,
+            max_failures,
+            log_dir,
+            state,
+            cache,
+            ci: Ci::detect(),
+        }
+    }
+
+    /// Change how many failures stop the next run
+    pub(crate) fn set_max_failures(&mut self, max_failures: usize) {
+        self.max_failures = max_failures;
+    }
+
+    /// Run the jobs, stopping once `max_failures` jobs have failed or the run is interrupted.
+    ///
+    /// Each job comes with its index in the plan, which names its log and CI group. There
+    /// is one report per job, in the order given. Jobs that never started are reported
+    /// as not run.
+    pub(crate) fn run(&self, jobs: &[(usize, Job)]) -> Result<Vec<JobReport>> {
+        let failures = AtomicUsize::new(0);
+        let stopped = || failures.load(Ordering::SeqCst) >= self.max_failures || interrupted();
+
+        if self.workers == 1 {
+            let mut reports = Vec::with_capacity(jobs.len());
+            for (index, job) in jobs {
+                if stopped() {
+                    reports.push(JobReport::skipped(job, TaskResult::NotRun));
+                    continue;
+                }
+                let report = self.run_job(*index, job, Console::Inherit, None)?;
+                if report.result().is_failure() {
+                    let _ = failures.fetch_add(1, Ordering::SeqCst);
+                }
+                reports.push(report);
+            }
+            return Ok(reports);
+        }
+
+        let workers = self.workers.min(jobs.len()).max(1);
+        print!("{}", Paint::cyan("    Parallel ").bold());
+        println!("Running {} job(s) on {workers} worker(s)", jobs.len());
+        println!();
+
+        let next = AtomicUsize::new(0);
+        let reports = Mutex::new(vec![None; jobs.len()]);
+
+        thread::scope(|s| {
+            let handles: Vec<_> = (0..workers)
+                .map(|worker| {
+                    let next = &next;
+                    let failures = &failures;
+                    let reports = &reports;
+                    let stopped = &stopped;
+                    let target_dir = self.target_dir.join(format!("worker-{worker}"));
+                    s.spawn(move || -> Result<()> {
+                        loop {
+                            if stopped() {
+                                return Ok(());
+                            }
+                            let slot = next.fetch_add(1, Ordering::SeqCst);
+                            let Some((index, job)) = jobs.get(slot) else {
+                                return Ok(());
+                            };
+                            let mut buffer = Vec::new();
+                            let report = self.run_job(
+                                *index,
+                                job,
+                                Console::Buffer(&mut buffer),
+                                Some(&target_dir),
+                            )?;
+                            {
+                                let mut stdout = io::stdout().lock();
+                                stdout.write_all(&buffer)?;
+                                stdout.flush()?;
+                            }
+                            if report.result().is_failure() {
+                                let _ = failures.fetch_add(1, Ordering::SeqCst);
+                            }
+                            reports
+                                .lock()
+                                .map_err(|_| anyhow!("job reports lock poisoned"))?[slot] =
+                                Some(report);
+                        }
+                    })
+                })
+                .collect();
+
+            handles
+                .into_iter()
+                .map(|handle| handle.join().map_err(|_| anyhow!("worker panicked"))?)
+                .collect::<Result<Vec<()>>>()
+        })?;
+
+        Ok(reports
+            .into_inner()
+            .map_err(|_| anyhow!("job reports lock poisoned"))?
+            .into_iter()
+            .zip(jobs)
+            .map(|(report, (_, job))| {
+                report.unwrap_or_else(|| JobReport::skipped(job, TaskResult::NotRun))
+            })
+            .collect())
+    }
+
+    /// Run a single job, unless it already passed in the run being resumed or is in the
+    /// cache, and record its result
+    fn run_job(
+        &self,
+        index: usize,
+        job: &Job,
+        mut console: Console<'_>,
+        target_dir: Option<&Path>,
+    ) -> Result<JobReport> {
+        let skipped = if self.state.as_ref().is_some_and(|state| state.passed(job)) {
+            Some((TaskResult::Resumed, "passed in a previous run"))
+        } else if self.cache.as_ref().is_some_and(|cache| cache.passed(job)) {
+            Some((TaskResult::Cached, "passed with the same inputs before"))
+        } else {
+            None
+        };
+        if let Some((result, reason)) = skipped {
+            let mut out = console.writer();
+            write!(out, "{}", Paint::cyan("    Skipping ").bold())?;
+            writeln!(
+                out,
+                "package={} features=[{}] {reason}",
+                job.package(),
+                job.feature_set()
+            )?;
+            writeln!(out)?;
+            let report = JobReport::skipped(job, result);
+            events::emit(&Event::job_finished(&report))?;
+            return Ok(report);
+        }
+
+        events::emit(&Event::JobStarted { job: job.into() })?;
+        if let Some(ci) = self.ci {
+            ci.start_group(&mut console.writer(), index, job)?;
+        }
+        let log = self.log_dir.as_ref().map(|dir| dir.job_log(index, job));
+        let report = job.execute(console.reborrow(), log.as_deref(), target_dir)?;
+        if let Some(ci) = self.ci {
+            ci.end_group(&mut console.writer(), index, &report)?;
+        }
+        events::emit(&Event::job_finished(&report))?;
+        // A dry run proves nothing about the job
+        if !job.dry_run() {
+            if let Some(state) = &self.state {
+                state.record(&report)?;
+            }
+            if let Some(cache) = &self.cache {
+                cache.store(&report)?;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::Pool;
+    use crate::runtime::{execute::Job, logs::LogDir};
+    use std::{env, fs, path::Path};
+
+    #[test]
+    fn logs_go_by_the_index_in_the_plan() {
+        let dir = env::temp_dir().join(format!("cargo-matrix-pool-{}", std::process::id()));
+        let log_dir = LogDir::create(&dir).unwrap();
+        let pool = Pool::new(2, dir.clone(), usize::MAX, Some(log_dir), None, None);
+        let mut job = Job::check("foo", &["a", "b"]);
+        let _ = job.set_dry_run(true);
+
+        // The second phase of a two phase run only gets the jobs that compiled
+        let reports = pool
+            .run(&[(2, job.compile_tests()), (4, job.clone())])
+            .unwrap();
+        let logs: Vec<_> = reports
+            .iter()
+            .map(|report| report.log().as_deref().and_then(Path::file_name).unwrap())
+            .collect();
+        assert_eq!(
+            logs,
+            ["0002-foo-default-a+b.build.log", "0004-foo-default-a+b.log"]
+        );
+        let _ = fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,346 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use anyhow::{Error, Result};
+use lazy_static::lazy_static;
+use std::{
+    collections::BTreeMap,
+    io::{self, BufRead, BufReader, Read, Write},
+    process::{Child, Command, ExitStatus, Stdio},
+    sync::{Mutex, MutexGuard, OnceLock, PoisonError},
+    thread,
+    time::{Duration, Instant},
+};
+use yansi::Paint;
+
+/// How often a running child is checked for completion
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// How long running jobs get to wind down after an interrupt before they are killed
+const INTERRUPT_GRACE: Duration = Duration::from_secs(10);
+
+/// When the first SIGINT/SIGTERM arrived, if one has
+static INTERRUPTED: OnceLock<Instant> = OnceLock::new();
+
+lazy_static! {
+    /// The process id of every running cargo, and whether it leads its own process group
+    static ref RUNNING: Mutex<BTreeMap<u32, bool>> = Mutex::new(BTreeMap::new());
+}
+
+/// How a child process ended
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum Exit {
+    Status(ExitStatus),
+    TimedOut,
+    Interrupted,
+}
+
+/// Install the SIGINT/SIGTERM handler.
+///
+/// The first signal is forwarded to every running job and stops new ones from
+/// starting. Running jobs are killed once the grace period is over, or right away
+/// on a second signal.
+pub(crate) fn handle_interrupts() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.set(Instant::now()).is_ok() {
+            eprintln!();
+            eprintln!(
+                "{} waiting up to {}s for running jobs, interrupt again to kill them",
+                Paint::yellow(" Interrupted").bold(),
+                INTERRUPT_GRACE.as_secs()
+            );
+            signal_running(Signal::Interrupt);
+        } else {
+            signal_running(Signal::Kill);
+        }
+    })?;
+    Ok(())
+}
+
+/// Has the run been interrupted
+pub(crate) fn interrupted() -> bool {
+    INTERRUPTED.get().is_some()
+}
+
+/// Turns a line of a child's stdout into what is kept of it
+pub(crate) type Relay<'a> = dyn FnMut(&[u8]) -> Result<Vec<u8>> + Send + 'a;
+
+/// What happens to the stdout and stderr of a child process
+pub(crate) enum Output<'a> {
+    /// The child writes straight to our stdout and stderr. It stays in our process group,
+    /// so it can read from the terminal and gets Ctrl-C from it, and a timeout or the end
+    /// of the grace period only kills the child itself.
+    Inherit,
+    /// Both streams are collected into one buffer in the order they arrive, and
+    /// optionally echoed to our own stdout and stderr as well
+    Capture { buffer: &'a mut Vec<u8>, echo: bool },
+    /// As `Capture`, except each line of stdout is handed to `relay` as it arrives, for
+    /// output that is parsed rather than read. What `relay` returns is kept and echoed
+    /// in place of the line.
+    Split {
+        buffer: &'a mut Vec<u8>,
+        echo: bool,
+        relay: &'a mut Relay<'a>,
+    },
+}
+
+/// Run the command to completion, killing its whole process group if it outlives the
+/// timeout or the grace period after an interrupt
+pub(crate) fn run(
+    cmd: &mut Command,
+    output: Output<'_>,
+    timeout: Option<Duration>,
+) -> Result<Exit> {
+    // Put cargo in its own process group so rustc, linkers and test binaries go with it,
+    // and so signals reach them through us. A group of its own is in the background,
+    // where reading the terminal stops it, so this is only done when we hold its output
+    // and its input is closed.
+    let group = cfg!(unix) && !matches!(output, Output::Inherit);
+    #[cfg(unix)]
+    if group {
+        use std::os::unix::process::CommandExt;
+        let _ = cmd.process_group(0).stdin(Stdio::null());
+    }
+
+    if interrupted() {
+        return Ok(Exit::Interrupted);
+    }
+
+    let (buffer, echo, relay) = match output {
+        Output::Inherit => {
+            let mut child = cmd
+                .stdout(Stdio::inherit())
+                .stderr(Stdio::inherit())
+                .spawn()?;
+            return wait(&mut child, timeout, group);
+        }
+        Output::Capture { buffer, echo } => (buffer, echo, None),
+        Output::Split {
+            buffer,
+            echo,
+            relay,
+        } => (buffer, echo, Some(relay)),
+    };
+
+    let mut child = cmd.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn()?;
+    let stdout = child.stdout.take();
+    let stderr = child.stderr.take();
+    let shared = Mutex::new(buffer);
+    let failed: Mutex<Option<Error>> = Mutex::new(None);
+
+    thread::scope(|s| {
+        let pump = |mut reader: Box<dyn Read + Send>,
+                    mut echo: Option<Box<dyn Write>>,
+                    target: &Mutex<&mut Vec<u8>>| {
+            let mut chunk = [0u8; 8192];
+            while let Ok(read) = reader.read(&mut chunk) {
+                if read == 0 {
+                    break;
+                }
+                if let Some(echo) = &mut echo {
+                    let _ = echo.write_all(&chunk[..read]);
+                    let _ = echo.flush();
+                }
+                if let Ok(mut buffer) = target.lock() {
+                    buffer.extend_from_slice(&chunk[..read]);
+                }
+            }
+        };
+        match (stdout, relay) {
+            (Some(stdout), Some(relay)) => {
+                let (shared, failed) = (&shared, &failed);
+                let _ = s.spawn(move || {
+                    let mut reader = BufReader::new(stdout);
+                    let mut line = Vec::new();
+                    while matches!(reader.read_until(b'\n', &mut line), Ok(read) if read > 0) {
+                        match relay(&line) {
+                            Ok(kept) => {
+                                if echo {
+                                    let mut out = io::stdout().lock();
+                                    let _ = out.write_all(&kept);
+                                    let _ = out.flush();
+                                }
+                                if let Ok(mut buffer) = shared.lock() {
+                                    buffer.extend_from_slice(&kept);
+                                }
+                            }
+                            Err(error) => {
+                                if let Ok(mut failed) = failed.lock() {
+                                    let _ = failed.get_or_insert(error);
+                                }
+                            }
+                        }
+                        // Not `line.clear()`, which resolves to yansi's `Paint::clear`
+                        Vec::clear(&mut line);
+                    }
+                });
+            }
+            (Some(stdout), None) => {
+                let shared = &shared;
+                let _ = s.spawn(move || {
+                    let echo = echo.then(|| Box::new(io::stdout()) as Box<dyn Write>);
+                    pump(Box::new(stdout), echo, shared);
+                });
+            }
+            (None, _) => {}
+        }
+        if let Some(stderr) = stderr {
+            let shared = &shared;
+            let _ = s.spawn(move || {
+                let echo = echo.then(|| Box::new(io::stderr()) as Box<dyn Write>);
+                pump(Box::new(stderr), echo, shared);
+            });
+        }
+        wait(&mut child, timeout, group)
+    })
+    .and_then(
+        |exit| match failed.into_inner().unwrap_or_else(|e| e.into_inner()) {
+            Some(error) => Err(error),
+            None => Ok(exit),
+        },
+    )
+}
+
+fn wait(child: &mut Child, timeout: Option<Duration>, group: bool) -> Result<Exit> {
+    let _ = running().insert(child.id(), group);
+    // The interrupt may have landed between the spawn and the registration
+    if interrupted() {
+        signal(child.id(), group, Signal::Interrupt);
+    }
+    let exit = poll(child, timeout, group);
+    let _ = running().remove(&child.id());
+    exit
+}
+
+fn poll(child: &mut Child, timeout: Option<Duration>, group: bool) -> Result<Exit> {
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    loop {
+        if let Some(status) = child.try_wait()? {
+            if status.success() || !interrupted() {
+                return Ok(Exit::Status(status));
+            }
+            // Build scripts and test binaries can outlive an interrupted cargo, and would
+            // hold on to our pipes until they finish
+            kill(child, group)?;
+            return Ok(Exit::Interrupted);
+        }
+        if deadline.is_some_and(|deadline| Instant::now() >= deadline) {
+            kill(child, group)?;
+            let _ = child.wait()?;
+            return Ok(Exit::TimedOut);
+        }
+        if INTERRUPTED
+            .get()
+            .is_some_and(|at| at.elapsed() >= INTERRUPT_GRACE)
+        {
+            kill(child, group)?;
+            let _ = child.wait()?;
+            return Ok(Exit::Interrupted);
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+fn running() -> MutexGuard<'static, BTreeMap<u32, bool>> {
+    // A panic while holding the lock leaves the set itself intact
+    RUNNING.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Signal {
+    Interrupt,
+    Kill,
+}
+
+fn signal_running(signal: Signal) {
+    for (pid, group) in running().iter() {
+        self::signal(*pid, *group, signal);
+    }
+}
+
+#[cfg(unix)]
+fn signal(pid: u32, group: bool, signal: Signal) {
+    use nix::sys::signal::{kill, killpg, Signal as NixSignal};
+    use nix::unistd::Pid;
+
+    let signal = match signal {
+        Signal::Interrupt => NixSignal::SIGINT,
+        Signal::Kill => NixSignal::SIGKILL,
+    };
+    if let Ok(pid) = i32::try_from(pid) {
+        // The process may already be gone, which is fine
+        let _ = if group {
+            killpg(Pid::from_raw(pid), signal)
+        } else {
+            kill(Pid::from_raw(pid), signal)
+        };
+    }
+}
+
+// Console processes on Windows all receive the Ctrl-C themselves, and the grace
+// period is enforced by killing the child from `poll`
+#[cfg(not(unix))]
+fn signal(_pid: u32, _group: bool, _signal: Signal) {}
+
+#[cfg(unix)]
+fn kill(child: &mut Child, group: bool) -> Result<()> {
+    use nix::{
+        errno::Errno,
+        sys::signal::{killpg, Signal},
+        unistd::Pid,
+    };
+
+    if !group {
+        return Ok(child.kill()?);
+    }
+    match killpg(Pid::from_raw(i32::try_from(child.id())?), Signal::SIGKILL) {
+        // The group may already be gone if cargo exited right at the deadline
+        Ok(()) | Err(Errno::ESRCH) => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(not(unix))]
+fn kill(child: &mut Child, _group: bool) -> Result<()> {
+    Ok(child.kill()?)
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod test {
+    use super::{run, Exit, Output};
+    use std::{fs, process::Command};
+
+    /// The process group of a process, from its `stat`
+    fn group(stat: &str) -> &str {
+        let fields = &stat[stat.rfind(')').unwrap() + 2..];
+        fields.split(' ').nth(2).unwrap()
+    }
+
+    #[test]
+    fn only_children_we_hold_the_output_of_get_a_group() {
+        let stat = fs::read_to_string("/proc/self/stat").unwrap();
+        let ours = group(&stat);
+        let in_our_group = |output: Output<'_>| {
+            let script = format!("[ \"$(cut -d' ' -f5 /proc/$$/stat)\" = {ours} ]");
+            let mut cmd = Command::new("sh");
+            let _ = cmd.args(["-c", &script]);
+            match run(&mut cmd, output, None).unwrap() {
+                Exit::Status(status) => status.success(),
+                exit => panic!("sh did not exit: {exit:?}"),
+            }
+        };
+
+        // On the terminal, Ctrl-C has to reach it and it may read from it
+        assert!(in_our_group(Output::Inherit));
+        let mut buffer = Vec::new();
+        assert!(!in_our_group(Output::Capture {
+            buffer: &mut buffer,
+            echo: false,
+        }));
+    }
+}
@@ -0,0 +1,289 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::{by_package, Entry, Report};
+use crate::feature::{Feature, FeatureSet};
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{cmp::Reverse, collections::BTreeSet};
+
+/// One or two features, present or left out, that every set of a package matching them
+/// failed with
+#[derive(Clone, CopyGetters, Debug, Deserialize, Getters, Serialize)]
+pub(crate) struct Culprit {
+    #[getset(get = "pub(crate)")]
+    package: String,
+    #[getset(get = "pub(crate)")]
+    channel: String,
+    /// Features the failing sets have
+    with: FeatureSet,
+    /// Features the failing sets do not have
+    without: FeatureSet,
+    /// How many sets match, every one of them failed
+    #[getset(get_copy = "pub(crate)")]
+    failed: usize,
+    /// Two features that pass apart but fail together
+    non_additive: bool,
+    #[getset(get = "pub(crate)")]
+    message: String,
+}
+
+/// A feature, and whether a set has to have it or leave it out
+type Literal<'a> = (&'a Feature, bool);
+
+/// Blame the failures of each package on single features or pairs of them.
+///
+/// A candidate is a feature or a pair of features, each either present or left out,
+/// that only failing sets match. Pairs already explained by one of their features are
+/// dropped, and the candidates covering the most failures are kept until every failure
+/// is covered or none is left.
+pub(crate) fn find(report: &Report) -> Vec<Culprit> {
+    let mut culprits = Vec::new();
+    for ((package, channel), entries) in by_package(report) {
+        let entries: Vec<&Entry> = entries
+            .into_iter()
+            .filter(|entry| entry.result().is_pass() || entry.result().is_failure())
+            .collect();
+        let failing: Vec<bool> = entries.iter().map(|e| e.result().is_failure()).collect();
+        // Nothing to tell apart when every set passed, or every set failed
+        if failing.iter().all(|failed| *failed) || !failing.iter().any(|failed| *failed) {
+            continue;
+        }
+        // Features in every set, like `always_include` ones, cannot tell sets apart
+        let features: BTreeSet<&Feature> = entries
+            .iter()
+            .flat_map(|entry| entry.features().iter())
+            .filter(|feature| !entries.iter().all(|e| e.features().contains(*feature)))
+            .collect();
+        let literals: Vec<Literal<'_>> = features
+            .iter()
+            .flat_map(|feature| [(*feature, true), (*feature, false)])
+            .collect();
+
+        let matches = |conjunction: &[Literal<'_>]| -> Vec<usize> {
+            (0..entries.len())
+                .filter(|index| {
+                    conjunction.iter().all(|(feature, present)| {
+                        entries[*index].features().contains(*feature) == *present
+                    })
+                })
+                .collect()
+        };
+        let explains =
+            |matched: &[usize]| !matched.is_empty() && matched.iter().all(|index| failing[*index]);
+
+        let mut candidates: Vec<(Vec<Literal<'_>>, Vec<usize>)> = Vec::new();
+        let singles: Vec<Literal<'_>> = literals
+            .iter()
+            .copied()
+            .filter(|literal| explains(&matches(&[*literal])))
+            .collect();
+        for literal in &singles {
+            candidates.push((vec![*literal], matches(&[*literal])));
+        }
+        for (position, first) in literals.iter().enumerate() {
+            for second in &literals[position + 1..] {
+                if first.0 == second.0 || singles.contains(first) || singles.contains(second) {
+                    continue;
+                }
+                let matched = matches(&[*first, *second]);
+                if explains(&matched) {
+                    candidates.push((vec![*first, *second], matched));
+                }
+            }
+        }
+        candidates
+            .sort_by_key(|(conjunction, matched)| (Reverse(matched.len()), conjunction.len()));
+
+        let mut covered = BTreeSet::new();
+        for (conjunction, matched) in candidates {
+            if covered.len() == failing.iter().filter(|failed| **failed).count() {
+                break;
+            }
+            if matched.iter().all(|index| covered.contains(index)) {
+                continue;
+            }
+            covered.extend(matched.iter().copied());
+            culprits.push(Culprit::new(
+                package,
+                channel,
+                &conjunction,
+                matched.len(),
+                &entries,
+            ));
+        }
+    }
+    culprits
+}
+
+impl Culprit {
+    fn new(
+        package: &str,
+        channel: &str,
+        conjunction: &[Literal<'_>],
+        failed: usize,
+        entries: &[&Entry],
+    ) -> Self {
+        let with: FeatureSet = conjunction
+            .iter()
+            .filter(|(_, present)| *present)
+            .map(|(feature, _)| (*feature).clone())
+            .collect();
+        let without: FeatureSet = conjunction
+            .iter()
+            .filter(|(_, present)| !*present)
+            .map(|(feature, _)| (*feature).clone())
+            .collect();
+
+        // Each of the two passes in some set without the other
+        let passes_without = |feature: &Feature, other: &Feature| {
+            entries.iter().any(|entry| {
+                entry.result().is_pass()
+                    && entry.features().contains(feature)
+                    && !entry.features().contains(other)
+            })
+        };
+        let pair: Vec<&Feature> = with.iter().collect();
+        let non_additive = without.is_empty()
+            && pair.len() == 2
+            && passes_without(pair[0], pair[1])
+            && passes_without(pair[1], pair[0]);
+
+        let message = if non_additive {
+            format!(
+                "`{}` and `{}` each pass without the other but fail together (non-additive)",
+                pair[0], pair[1]
+            )
+        } else {
+            let mut parts = Vec::new();
+            if !with.is_empty() {
+                parts.push(format!("containing {}", quoted(&with)));
+            }
+            if !without.is_empty() {
+                let joiner = if with.is_empty() { "without" } else { "not" };
+                parts.push(format!("{joiner} {}", quoted(&without)));
+            }
+            format!("every set {} fails", parts.join(" and "))
+        };
+
+        Self {
+            package: package.to_string(),
+            channel: channel.to_string(),
+            with,
+            without,
+            failed,
+            non_additive,
+            message,
+        }
+    }
+}
+
+fn quoted(set: &FeatureSet) -> String {
+    set.iter()
+        .map(|feature| format!("`{feature}`"))
+        .collect::<Vec<_>>()
+        .join(" and ")
+}
+
+#[cfg(test)]
+mod test {
+    use super::{find, Culprit};
+    use crate::{
+        feature::FeatureSet,
+        runtime::report::{Entry, Outcome, Report},
+    };
+
+    /// A report with a job for every subset of `features`, failing where `fails` says
+    fn report(features: &[&str], fails: impl Fn(&[&str]) -> bool) -> Report {
+        let jobs = (0..1_usize << features.len())
+            .map(|bits| {
+                let set: Vec<&str> = features
+                    .iter()
+                    .enumerate()
+                    .filter(|(index, _)| bits & (1 << index) != 0)
+                    .map(|(_, feature)| *feature)
+                    .collect();
+                let failed = fails(&set);
+                Entry {
+                    id: format!("{bits:012x}"),
+                    package: "foo".to_string(),
+                    channel: "default".to_string(),
+                    features: set.iter().copied().map(Into::into).collect::<FeatureSet>(),
+                    command: String::new(),
+                    outcome: if failed { Outcome::Fail } else { Outcome::Pass },
+                    exit_code: Some(i32::from(failed)),
+                    duration: 1.0,
+                    attempts: 1,
+                    log: None,
+                    error: None,
+                }
+            })
+            .collect();
+        Report::from_entries(jobs)
+    }
+
+    fn messages(culprits: &[Culprit]) -> Vec<&str> {
+        culprits
+            .iter()
+            .map(|culprit| culprit.message().as_str())
+            .collect()
+    }
+
+    #[test]
+    fn blames_a_feature_without_another() {
+        let culprits = find(&report(&["a", "std", "tls"], |set| {
+            set.contains(&"tls") && !set.contains(&"std")
+        }));
+        assert_eq!(
+            messages(&culprits),
+            ["every set containing `tls` and not `std` fails"]
+        );
+        assert_eq!(culprits[0].failed(), 2);
+        assert!(!culprits[0].non_additive);
+    }
+
+    #[test]
+    fn blames_a_non_additive_pair() {
+        let culprits = find(&report(&["a", "b", "c"], |set| {
+            set.contains(&"a") && set.contains(&"b")
+        }));
+        assert_eq!(
+            messages(&culprits),
+            ["`a` and `b` each pass without the other but fail together (non-additive)"]
+        );
+        assert_eq!(culprits[0].failed(), 2);
+        assert!(culprits[0].non_additive);
+    }
+
+    #[test]
+    fn drops_pairs_a_single_feature_explains() {
+        let culprits = find(&report(&["a", "b", "c"], |set| set.contains(&"c")));
+        assert_eq!(messages(&culprits), ["every set containing `c` fails"]);
+        assert_eq!(culprits[0].failed(), 4);
+    }
+
+    #[test]
+    fn covers_every_failure() {
+        let culprits = find(&report(&["a", "b", "c"], |set| {
+            set.contains(&"c") || (set.contains(&"a") && set.contains(&"b"))
+        }));
+        assert_eq!(
+            messages(&culprits),
+            [
+                "every set containing `c` fails",
+                "`a` and `b` each pass without the other but fail together (non-additive)"
+            ]
+        );
+    }
+
+    #[test]
+    fn blames_nothing_without_both_passes_and_failures() {
+        assert!(find(&report(&["a", "b"], |_| false)).is_empty());
+        assert!(find(&report(&["a", "b"], |_| true)).is_empty());
+    }
+}
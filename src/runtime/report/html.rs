@@ -0,0 +1,378 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::{by_package, Entry, Report};
+use crate::{
+    feature::{Feature, FeatureSet},
+    runtime::execute::TaskResult,
+};
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    collections::{BTreeSet, HashMap},
+    fs,
+    io::Write,
+};
+
+/// How much of each job's log is embedded in the page
+const LOG_LINES: usize = 500;
+
+/// Size of the Hasse diagram layout, in pixels
+const CHAR_WIDTH: usize = 7;
+const NODE_HEIGHT: usize = 22;
+const NODE_GAP: usize = 12;
+const LAYER_GAP: usize = 60;
+const MARGIN: usize = 10;
+
+const STYLE: &str = r#"
+body { font-family: system-ui, sans-serif; margin: 2em; color: #1f2328; }
+h1 { font-size: 1.5em; }
+h2 { font-size: 1.2em; margin-top: 2em; border-bottom: 1px solid #d0d7de; }
+table { border-collapse: collapse; font-size: 0.9em; }
+th, td { border: 1px solid #d0d7de; padding: 2px 8px; text-align: center; }
+td.result { cursor: pointer; font-weight: bold; color: #fff; }
+.pass { background: #2da44e; fill: #2da44e; }
+.flaky { background: #bf8700; fill: #bf8700; }
+.fail { background: #cf222e; fill: #cf222e; }
+.other { background: #8c959f; fill: #8c959f; }
+.hasse { overflow: auto; max-width: 100%; border: 1px solid #d0d7de; margin: 1em 0; }
+.hasse line { stroke: #8c959f; }
+.hasse g { cursor: pointer; }
+.hasse text { font: 11px monospace; fill: #fff; }
+#details { position: fixed; right: 0; top: 0; bottom: 0; width: 45%; overflow: auto;
+  background: #f6f8fa; border-left: 1px solid #d0d7de; padding: 1em; display: none; }
+#details pre { white-space: pre-wrap; word-break: break-all; font-size: 0.8em; }
+#details button { float: right; }
+"#;
+
+const SCRIPT: &str = r#"
+const jobs = JSON.parse(document.getElementById('jobs').textContent);
+const details = document.getElementById('details');
+document.querySelectorAll('[data-job]').forEach((el) => {
+  el.addEventListener('click', () => {
+    const job = jobs[Number(el.dataset.job)];
+    document.getElementById('d-title').textContent =
+      job.package + ' (' + job.channel + ') [' + job.features.join(',') + ']';
+    document.getElementById('d-result').textContent = job.result;
+    document.getElementById('d-command').textContent = job.command;
+    document.getElementById('d-log').textContent = job.log || '(no log captured)';
+    details.style.display = 'block';
+  });
+});
+document.getElementById('d-close').addEventListener('click', () => {
+  details.style.display = 'none';
+});
+"#;
+
+/// What the page script shows when a job is clicked
+#[derive(Serialize)]
+struct Details<'a> {
+    package: &'a str,
+    channel: &'a str,
+    features: Vec<&'a str>,
+    result: String,
+    command: &'a str,
+    log: Option<String>,
+}
+
+/// One page with a grid and a Hasse diagram of the feature sets of each package,
+/// with nothing loaded from elsewhere
+pub(super) fn write(report: &Report, out: &mut dyn Write) -> Result<()> {
+    let (passed, failed) = report.totals();
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\">")?;
+    writeln!(out, "<head>")?;
+    writeln!(out, "<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>cargo-matrix results</title>")?;
+    writeln!(out, "<style>{STYLE}</style>")?;
+    writeln!(out, "</head>")?;
+    writeln!(out, "<body>")?;
+    writeln!(out, "<h1>cargo-matrix results</h1>")?;
+    writeln!(
+        out,
+        "<p>{} job(s), {passed} passed, {failed} failed. Click a result for its command and log.</p>",
+        report.jobs().len()
+    )?;
+
+    // Entries are numbered by their position in the report, which the script looks up
+    let indices: HashMap<&str, usize> = report
+        .jobs()
+        .iter()
+        .enumerate()
+        .rev()
+        .map(|(index, entry)| (entry.id().as_str(), index))
+        .collect();
+    let index = |entry: &Entry| {
+        indices
+            .get(entry.id().as_str())
+            .copied()
+            .unwrap_or_default()
+    };
+
+    for ((package, channel), entries) in by_package(report) {
+        writeln!(out, "<h2>{} ({})</h2>", escape(package), escape(channel))?;
+        hasse(out, &entries, &index)?;
+        grid(out, &entries, &index)?;
+    }
+
+    let details: Vec<Details<'_>> = report.jobs().iter().map(details).collect();
+    // Nothing in the data may close the script element early
+    let data = serde_json::to_string(&details)?.replace("</", "<\\/");
+    writeln!(
+        out,
+        "<script type=\"application/json\" id=\"jobs\">{data}</script>"
+    )?;
+    writeln!(out, "<div id=\"details\">")?;
+    writeln!(out, "<button id=\"d-close\">close</button>")?;
+    writeln!(out, "<h3 id=\"d-title\"></h3>")?;
+    writeln!(out, "<p id=\"d-result\"></p>")?;
+    writeln!(out, "<h4>Reproduce</h4><pre id=\"d-command\"></pre>")?;
+    writeln!(
+        out,
+        "<h4>Log (last {LOG_LINES} lines)</h4><pre id=\"d-log\"></pre>"
+    )?;
+    writeln!(out, "</div>")?;
+    writeln!(out, "<script>{SCRIPT}</script>")?;
+    writeln!(out, "</body>")?;
+    writeln!(out, "</html>")?;
+    Ok(())
+}
+
+/// A row per feature set, marking the features in it, and its result
+fn grid(out: &mut dyn Write, entries: &[&Entry], index: &dyn Fn(&Entry) -> usize) -> Result<()> {
+    let features: BTreeSet<&Feature> = entries
+        .iter()
+        .flat_map(|entry| entry.features().iter())
+        .collect();
+
+    writeln!(out, "<table>")?;
+    write!(out, "<tr>")?;
+    for feature in &features {
+        write!(out, "<th>{}</th>", escape(&feature.0))?;
+    }
+    writeln!(out, "<th>result</th><th>duration</th></tr>")?;
+    for entry in entries {
+        write!(out, "<tr>")?;
+        for feature in &features {
+            let mark = if entry.features().contains(*feature) {
+                "&#x2713;"
+            } else {
+                ""
+            };
+            write!(out, "<td>{mark}</td>")?;
+        }
+        writeln!(
+            out,
+            "<td class=\"result {}\" data-job=\"{}\">{}</td><td>{:.1}s</td></tr>",
+            class(entry.result()),
+            index(entry),
+            label(entry.result()),
+            entry.duration()
+        )?;
+    }
+    writeln!(out, "</table>")?;
+    Ok(())
+}
+
+/// The feature sets laid out by size, with an edge from each set to the smallest sets
+/// in the matrix that contain it
+fn hasse(out: &mut dyn Write, entries: &[&Entry], index: &dyn Fn(&Entry) -> usize) -> Result<()> {
+    let max_size = entries
+        .iter()
+        .map(|entry| entry.features().len())
+        .max()
+        .unwrap_or_default();
+    let layers: Vec<Vec<&Entry>> = (0..=max_size)
+        .map(|size| {
+            entries
+                .iter()
+                .copied()
+                .filter(|entry| entry.features().len() == size)
+                .collect()
+        })
+        .filter(|layer: &Vec<&Entry>| !layer.is_empty())
+        .collect();
+
+    // Place every node, the smallest sets at the bottom
+    let node_width = |entry: &Entry| (name(entry).len() * CHAR_WIDTH) + 2 * NODE_GAP;
+    let layer_width = |layer: &[&Entry]| {
+        layer
+            .iter()
+            .map(|entry| node_width(entry) + NODE_GAP)
+            .sum::<usize>()
+    };
+    let width = layers
+        .iter()
+        .map(|layer| layer_width(layer))
+        .max()
+        .unwrap_or_default()
+        + 2 * MARGIN;
+    let height = layers.len() * (NODE_HEIGHT + LAYER_GAP) - LAYER_GAP + 2 * MARGIN;
+    let mut nodes: Vec<(&Entry, usize, usize, usize)> = Vec::new();
+    for (depth, layer) in layers.iter().enumerate() {
+        let y = height - MARGIN - NODE_HEIGHT - depth * (NODE_HEIGHT + LAYER_GAP);
+        let mut x = (width - layer_width(layer)) / 2;
+        for entry in layer {
+            nodes.push((entry, x, y, node_width(entry)));
+            x += node_width(entry) + NODE_GAP;
+        }
+    }
+
+    writeln!(out, "<div class=\"hasse\">")?;
+    writeln!(
+        out,
+        "<svg xmlns=\"http://www.w3.org/2000/svg\" width=\"{width}\" height=\"{height}\">"
+    )?;
+    let sets: Vec<&FeatureSet> = nodes.iter().map(|(entry, ..)| entry.features()).collect();
+    for (lower, upper) in covers(&sets) {
+        let (_, lx, ly, lw) = nodes[lower];
+        let (_, ux, uy, uw) = nodes[upper];
+        writeln!(
+            out,
+            "<line x1=\"{}\" y1=\"{ly}\" x2=\"{}\" y2=\"{}\"/>",
+            lx + lw / 2,
+            ux + uw / 2,
+            uy + NODE_HEIGHT
+        )?;
+    }
+    for (entry, x, y, w) in &nodes {
+        writeln!(
+            out,
+            "<g data-job=\"{}\"><title>{}</title><rect class=\"{}\" x=\"{x}\" y=\"{y}\" width=\"{w}\" height=\"{NODE_HEIGHT}\" rx=\"4\"/><text x=\"{}\" y=\"{}\" text-anchor=\"middle\">{}</text></g>",
+            index(entry),
+            escape(&format!("[{}] {}", entry.features(), entry.result().label())),
+            class(entry.result()),
+            x + w / 2,
+            y + NODE_HEIGHT - 7,
+            escape(&name(entry))
+        )?;
+    }
+    writeln!(out, "</svg>")?;
+    writeln!(out, "</div>")?;
+    Ok(())
+}
+
+/// Every pair of sets, by index, where the second is a strict superset of the first with
+/// no set in between.
+///
+/// What covers a set is among the set plus each feature. Only when one of those is
+/// missing, i.e. skipped, are the smallest sets holding it looked for instead. The
+/// smallest of the candidates are the covers.
+fn covers(sets: &[&FeatureSet]) -> Vec<(usize, usize)> {
+    let mut by_set: HashMap<&FeatureSet, Vec<usize>> = HashMap::new();
+    for (index, set) in sets.iter().enumerate() {
+        by_set.entry(*set).or_default().push(index);
+    }
+    let features: BTreeSet<&Feature> = sets.iter().flat_map(|set| set.iter()).collect();
+    let mut edges = Vec::new();
+    for (lower, set) in sets.iter().enumerate() {
+        let mut candidates = BTreeSet::new();
+        for feature in features.iter().filter(|feature| !set.contains(**feature)) {
+            let mut parent = (*set).clone();
+            let _ = parent.insert((*feature).clone());
+            match by_set.get_key_value(&parent) {
+                Some((parent, _)) => {
+                    let _ = candidates.insert(*parent);
+                }
+                None => candidates.extend(smallest(
+                    sets.iter()
+                        .copied()
+                        .filter(|upper| parent.is_subset(upper))
+                        .collect(),
+                )),
+            }
+        }
+        for upper in smallest(candidates.into_iter().collect()) {
+            edges.extend(by_set[upper].iter().map(|upper| (lower, *upper)));
+        }
+    }
+    edges
+}
+
+/// The sets with no other of the sets inside them
+fn smallest(sets: Vec<&FeatureSet>) -> Vec<&FeatureSet> {
+    let strict_subset = |a: &FeatureSet, b: &FeatureSet| a.len() < b.len() && a.is_subset(b);
+    sets.iter()
+        .copied()
+        .filter(|set| !sets.iter().any(|other| strict_subset(other, set)))
+        .collect()
+}
+
+fn details(entry: &Entry) -> Details<'_> {
+    let log = entry.log().as_ref().and_then(|path| {
+        let text = fs::read_to_string(path).ok()?;
+        let lines: Vec<&str> = text.lines().collect();
+        Some(lines[lines.len().saturating_sub(LOG_LINES)..].join("\n"))
+    });
+    Details {
+        package: entry.package(),
+        channel: entry.channel(),
+        features: entry.features().iter().map(|f| f.0.as_str()).collect(),
+        result: label(entry.result()),
+        command: entry.command(),
+        log,
+    }
+}
+
+fn name(entry: &Entry) -> String {
+    if entry.features().is_empty() {
+        "(none)".to_string()
+    } else {
+        entry.features().to_string()
+    }
+}
+
+fn label(result: TaskResult) -> String {
+    match result.code() {
+        Some(code) if result.is_failure() => format!("{} ({code})", result.label()),
+        _ => result.label().to_string(),
+    }
+}
+
+fn class(result: TaskResult) -> &'static str {
+    match result {
+        TaskResult::Flaky => "flaky",
+        _ if result.is_pass() => "pass",
+        _ if result.is_failure() => "fail",
+        _ => "other",
+    }
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod test {
+    use super::covers;
+    use crate::feature::FeatureSet;
+
+    #[test]
+    fn covers_skip_over_missing_sets() {
+        let sets: Vec<FeatureSet> = [&[][..], &["a"], &["b"], &["a", "b", "c"], &["c"]]
+            .iter()
+            .map(|set| set.iter().copied().map(Into::into).collect())
+            .collect();
+        let sets: Vec<&FeatureSet> = sets.iter().collect();
+        let mut edges = covers(&sets);
+        edges.sort_unstable();
+        // No set of two features is there, so [a, b, c] covers each set of one
+        assert_eq!(edges, [(0, 1), (0, 2), (0, 4), (1, 3), (2, 3), (4, 3)]);
+    }
+}
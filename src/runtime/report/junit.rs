@@ -0,0 +1,167 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::{by_package, Entry, Outcome, Report};
+use crate::runtime::execute::TaskResult;
+use anyhow::Result;
+use quick_xml::{
+    events::{BytesDecl, BytesEnd, BytesStart, BytesText, Event},
+    Writer,
+};
+use std::io::Write;
+
+/// One test suite per package and channel, with one test case per feature set
+pub(super) fn write(report: &Report, out: &mut dyn Write) -> Result<()> {
+    let mut writer = Writer::new_with_indent(out, b' ', 4);
+    writer.write_event(Event::Decl(BytesDecl::new("1.0", Some("UTF-8"), None)))?;
+
+    let jobs = report.jobs();
+    let mut root = BytesStart::new("testsuites");
+    root.push_attribute(("name", "cargo-matrix"));
+    push_counts(&mut root, jobs.iter());
+    writer.write_event(Event::Start(root))?;
+
+    for ((package, channel), entries) in by_package(report) {
+        let mut suite = BytesStart::new("testsuite");
+        suite.push_attribute(("name", package));
+        push_counts(&mut suite, entries.iter().copied());
+        writer.write_event(Event::Start(suite))?;
+        for entry in entries {
+            write_case(&mut writer, entry, package, channel)?;
+        }
+        writer.write_event(Event::End(BytesEnd::new("testsuite")))?;
+    }
+
+    writer.write_event(Event::End(BytesEnd::new("testsuites")))?;
+    Ok(())
+}
+
+fn write_case<W: Write>(
+    writer: &mut Writer<W>,
+    entry: &Entry,
+    package: &str,
+    channel: &str,
+) -> Result<()> {
+    let name = format!("{channel} [{}]", entry.features());
+    let mut case = BytesStart::new("testcase");
+    case.push_attribute(("name", name.as_str()));
+    case.push_attribute(("classname", package));
+    case.push_attribute(("time", format!("{:.3}", entry.duration()).as_str()));
+
+    let result = entry.result();
+    let detail = match entry.log() {
+        Some(log) => format!("{}\nlog: {}", entry.command(), log.display()),
+        None => entry.command().clone(),
+    };
+    let (element, message) = match result {
+        TaskResult::Fail(code) | TaskResult::CompileError(code) => {
+            ("failure", format!("exit code {code}"))
+        }
+        TaskResult::Timeout => ("failure", "timed out".to_string()),
+        TaskResult::Interrupted | TaskResult::NotRun => ("skipped", result.label().to_lowercase()),
+        TaskResult::Flaky => {
+            // A pass, with a flaky failure for each attempt before the one that passed
+            // as surefire reports reruns
+            writer.write_event(Event::Start(case))?;
+            for attempt in 1..entry.attempts() {
+                let mut flaky = BytesStart::new("flakyFailure");
+                let message = format!("attempt {attempt} of {} failed", entry.attempts());
+                flaky.push_attribute(("message", message.as_str()));
+                flaky.push_attribute(("type", "fail"));
+                writer.write_event(Event::Start(flaky))?;
+                writer.write_event(Event::Text(BytesText::new(&detail)))?;
+                writer.write_event(Event::End(BytesEnd::new("flakyFailure")))?;
+            }
+            writer.write_event(Event::End(BytesEnd::new("testcase")))?;
+            return Ok(());
+        }
+        _ => {
+            writer.write_event(Event::Empty(case))?;
+            return Ok(());
+        }
+    };
+
+    writer.write_event(Event::Start(case))?;
+    let mut child = BytesStart::new(element);
+    child.push_attribute(("message", message.as_str()));
+    if element == "failure" {
+        let kind = match entry.outcome() {
+            Outcome::CompileError => "compile-error",
+            Outcome::Timeout => "timeout",
+            _ => "fail",
+        };
+        child.push_attribute(("type", kind));
+        writer.write_event(Event::Start(child))?;
+        writer.write_event(Event::Text(BytesText::new(&detail)))?;
+        writer.write_event(Event::End(BytesEnd::new(element)))?;
+    } else {
+        writer.write_event(Event::Empty(child))?;
+    }
+    writer.write_event(Event::End(BytesEnd::new("testcase")))?;
+    Ok(())
+}
+
+/// The `tests`, `failures`, `skipped` and `time` attributes for a group of entries
+fn push_counts<'a>(element: &mut BytesStart<'_>, entries: impl Iterator<Item = &'a Entry>) {
+    let (mut tests, mut failures, mut skipped, mut time) = (0, 0, 0, 0.0);
+    for entry in entries {
+        tests += 1;
+        time += entry.duration();
+        let result = entry.result();
+        if result.is_failure() {
+            failures += 1;
+        } else if matches!(result, TaskResult::Interrupted | TaskResult::NotRun) {
+            skipped += 1;
+        }
+    }
+    element.push_attribute(("tests", tests.to_string().as_str()));
+    element.push_attribute(("failures", failures.to_string().as_str()));
+    element.push_attribute(("errors", "0"));
+    element.push_attribute(("skipped", skipped.to_string().as_str()));
+    element.push_attribute(("time", format!("{time:.3}").as_str()));
+}
+
+#[cfg(test)]
+mod test {
+    use super::write;
+    use crate::runtime::{
+        execute::TaskResult,
+        report::{Entry, Report},
+    };
+
+    #[test]
+    fn writes_each_result() {
+        let report = Report::from_entries(vec![
+            Entry::of(&["a"], TaskResult::Success, 1),
+            Entry::of(&["a", "b"], TaskResult::CompileError(101), 1),
+            Entry::of(&["b"], TaskResult::Fail(101), 1),
+            Entry::of(&["c"], TaskResult::Flaky, 3),
+        ]);
+        let mut out = Vec::new();
+        write(&report, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<testsuites name="cargo-matrix" tests="4" failures="2" errors="0" skipped="0" time="4.000">
+    <testsuite name="foo" tests="4" failures="2" errors="0" skipped="0" time="4.000">
+        <testcase name="default [a]" classname="foo" time="1.000"/>
+        <testcase name="default [a,b]" classname="foo" time="1.000">
+            <failure message="exit code 101" type="compile-error">cargo check -p foo -F a,b</failure>
+        </testcase>
+        <testcase name="default [b]" classname="foo" time="1.000">
+            <failure message="exit code 101" type="fail">cargo check -p foo -F b</failure>
+        </testcase>
+        <testcase name="default [c]" classname="foo" time="1.000">
+            <flakyFailure message="attempt 1 of 3 failed" type="fail">cargo check -p foo -F c</flakyFailure>
+            <flakyFailure message="attempt 2 of 3 failed" type="fail">cargo check -p foo -F c</flakyFailure>
+        </testcase>
+    </testsuite>
+</testsuites>"#
+        );
+    }
+}
@@ -0,0 +1,147 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use super::{by_package, Entry, Report};
+use crate::{feature::Feature, runtime::execute::TaskResult};
+use anyhow::Result;
+use std::{collections::BTreeSet, io::Write};
+
+/// A pass/fail grid per package, with a column per feature, followed by the commands
+/// that reproduce each failure
+pub(super) fn write(report: &Report, out: &mut dyn Write) -> Result<()> {
+    let (passed, failed) = report.totals();
+    writeln!(out, "## cargo-matrix results")?;
+    writeln!(out)?;
+    writeln!(
+        out,
+        "{} job(s), {passed} passed, {failed} failed",
+        report.jobs().len()
+    )?;
+
+    for ((package, channel), entries) in by_package(report) {
+        writeln!(out)?;
+        writeln!(out, "### `{package}` ({channel})")?;
+        writeln!(out)?;
+        table(out, &entries)?;
+
+        let failures: Vec<&&Entry> = entries
+            .iter()
+            .filter(|entry| entry.result().is_failure())
+            .collect();
+        if !failures.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "<details><summary>Reproduce</summary>")?;
+            writeln!(out)?;
+            writeln!(out, "```sh")?;
+            for entry in failures {
+                writeln!(out, "{}", entry.command())?;
+            }
+            writeln!(out, "```")?;
+            writeln!(out)?;
+            writeln!(out, "</details>")?;
+        }
+    }
+    Ok(())
+}
+
+/// A row per feature set, marking the features in it, and its result
+fn table(out: &mut dyn Write, entries: &[&Entry]) -> Result<()> {
+    let features: BTreeSet<&Feature> = entries
+        .iter()
+        .flat_map(|entry| entry.features().iter())
+        .collect();
+
+    let mut header = String::from("|");
+    let mut rule = String::from("|");
+    for feature in &features {
+        header.push_str(&format!(" {feature} |"));
+        rule.push_str(":-:|");
+    }
+    header.push_str(" result | duration |");
+    rule.push_str("---|--:|");
+    writeln!(out, "{header}")?;
+    writeln!(out, "{rule}")?;
+
+    for entry in entries {
+        let mut row = String::from("|");
+        for feature in &features {
+            let mark = if entry.features().contains(*feature) {
+                "x"
+            } else {
+                ""
+            };
+            row.push_str(&format!(" {mark} |"));
+        }
+        row.push_str(&format!(" {} | {:.1}s |", cell(entry), entry.duration()));
+        writeln!(out, "{row}")?;
+    }
+    Ok(())
+}
+
+fn cell(entry: &Entry) -> String {
+    let result = entry.result();
+    let icon = match result {
+        TaskResult::Flaky => "⚠️",
+        _ if result.is_pass() => "✅",
+        _ if result.is_failure() => "❌",
+        _ => "⏸️",
+    };
+    match result.code() {
+        Some(code) if result.is_failure() => format!("{icon} {} ({code})", result.label()),
+        _ if matches!(result, TaskResult::Flaky) => {
+            format!("{icon} {} ({} attempts)", result.label(), entry.attempts())
+        }
+        _ => format!("{icon} {}", result.label()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::write;
+    use crate::runtime::{
+        execute::TaskResult,
+        report::{Entry, Report},
+    };
+
+    #[test]
+    fn writes_each_result() {
+        let report = Report::from_entries(vec![
+            Entry::of(&["a"], TaskResult::Success, 1),
+            Entry::of(&["a", "b"], TaskResult::CompileError(101), 1),
+            Entry::of(&["b"], TaskResult::Fail(101), 1),
+            Entry::of(&["c"], TaskResult::Flaky, 3),
+        ]);
+        let mut out = Vec::new();
+        write(&report, &mut out).unwrap();
+        assert_eq!(
+            String::from_utf8(out).unwrap(),
+            r#"## cargo-matrix results
+
+4 job(s), 2 passed, 2 failed
+
+### `foo` (default)
+
+| a | b | c | result | duration |
+|:-:|:-:|:-:|---|--:|
+| x |  |  | ✅ OK | 1.0s |
+| x | x |  | ❌ COMPILE ERROR (101) | 1.0s |
+|  | x |  | ❌ FAILED (101) | 1.0s |
+|  |  | x | ⚠️ FLAKY (3 attempts) | 1.0s |
+
+<details><summary>Reproduce</summary>
+
+```sh
+cargo check -p foo -F a,b
+cargo check -p foo -F b
+```
+
+</details>
+"#
+        );
+    }
+}
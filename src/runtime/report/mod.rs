@@ -0,0 +1,324 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+mod culprit;
+mod html;
+mod junit;
+mod markdown;
+
+pub(crate) use self::culprit::Culprit;
+
+use crate::{
+    feature::FeatureSet,
+    runtime::{
+        diagnostic::Diagnostic,
+        execute::{JobReport, TaskResult},
+    },
+};
+use anyhow::{anyhow, Context, Result};
+use getset::{CopyGetters, Getters};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt::{Display, Formatter},
+    fs::File,
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use yansi::Paint;
+
+/// Version of the JSON report layout
+const REPORT_VERSION: u32 = 2;
+
+/// The formats a report can be written in
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub(crate) enum Format {
+    Html,
+    Json,
+    Junit,
+    Markdown,
+}
+
+impl Display for Format {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Format::Html => write!(f, "html"),
+            Format::Json => write!(f, "json"),
+            Format::Junit => write!(f, "junit"),
+            Format::Markdown => write!(f, "markdown"),
+        }
+    }
+}
+
+/// A `<format>=<path>` report request from the command line
+#[derive(Clone, Debug, Getters)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct Spec {
+    format: Format,
+    path: PathBuf,
+}
+
+impl FromStr for Spec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, path) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected <format>=<path>, got '{s}'"))?;
+        let format = match format {
+            "html" => Format::Html,
+            "json" => Format::Json,
+            "junit" => Format::Junit,
+            "markdown" | "md" => Format::Markdown,
+            _ => {
+                return Err(format!(
+                    "unknown report format '{format}', expected html, json, junit or markdown"
+                ))
+            }
+        };
+        if path.is_empty() {
+            return Err(format!("missing path for the {format} report"));
+        }
+        Ok(Self {
+            format,
+            path: PathBuf::from(path),
+        })
+    }
+}
+
+/// The results of a run, as written to the JSON report
+#[derive(Clone, Debug, Deserialize, Getters, Serialize)]
+#[getset(get = "pub(crate)")]
+pub(crate) struct Report {
+    version: u32,
+    jobs: Vec<Entry>,
+    /// What the failures have in common, see `culprit::find`
+    #[serde(default)]
+    culprits: Vec<Culprit>,
+}
+
+impl Report {
+    pub(crate) fn new(reports: &[JobReport]) -> Self {
+        Self::from_entries(reports.iter().map(Entry::from).collect())
+    }
+
+    pub(crate) fn from_entries(jobs: Vec<Entry>) -> Self {
+        let mut report = Self {
+            version: REPORT_VERSION,
+            jobs,
+            culprits: Vec::new(),
+        };
+        report.culprits = culprit::find(&report);
+        report
+    }
+
+    /// Write the report in every requested format
+    pub(crate) fn write(&self, specs: &[Spec]) -> Result<()> {
+        for spec in specs {
+            let mut out = BufWriter::new(File::create(spec.path())?);
+            match spec.format() {
+                Format::Html => html::write(self, &mut out)?,
+                Format::Json => serde_json::to_writer_pretty(&mut out, self)?,
+                Format::Junit => junit::write(self, &mut out)?,
+                Format::Markdown => markdown::write(self, &mut out)?,
+            }
+            out.flush()?;
+            println!(
+                "{} Wrote the {} report to {}",
+                Paint::cyan("      Report").bold(),
+                spec.format(),
+                spec.path().display()
+            );
+        }
+        Ok(())
+    }
+
+    /// Read a JSON report written by an earlier run
+    pub(crate) fn read(path: &Path) -> Result<Self> {
+        let file = File::open(path)
+            .with_context(|| format!("cannot open the report {}", path.display()))?;
+        let report: Self = serde_json::from_reader(BufReader::new(file))
+            .with_context(|| format!("{} is not a JSON report", path.display()))?;
+        if report.version != REPORT_VERSION {
+            return Err(anyhow!(
+                "{} is a version {} report, expected version {REPORT_VERSION}",
+                path.display(),
+                report.version
+            ));
+        }
+        Ok(report)
+    }
+
+    /// The Markdown form of the report
+    pub(crate) fn write_markdown(&self, out: &mut dyn Write) -> Result<()> {
+        markdown::write(self, out)
+    }
+
+    /// How many jobs passed and how many failed
+    pub(crate) fn totals(&self) -> (usize, usize) {
+        let passed = self.jobs.iter().filter(|e| e.result().is_pass()).count();
+        let failed = self.jobs.iter().filter(|e| e.result().is_failure()).count();
+        (passed, failed)
+    }
+}
+
+/// One job of the run
+#[derive(Clone, CopyGetters, Debug, Deserialize, Getters, Serialize)]
+pub(crate) struct Entry {
+    /// The id of the job, the same as the one in plans and events
+    #[getset(get = "pub(crate)")]
+    id: String,
+    #[getset(get = "pub(crate)")]
+    package: String,
+    #[getset(get = "pub(crate)")]
+    channel: String,
+    #[getset(get = "pub(crate)")]
+    features: FeatureSet,
+    #[getset(get = "pub(crate)")]
+    command: String,
+    #[getset(get_copy = "pub(crate)")]
+    outcome: Outcome,
+    #[getset(get_copy = "pub(crate)")]
+    exit_code: Option<i32>,
+    /// Wall clock time in seconds
+    #[getset(get_copy = "pub(crate)")]
+    duration: f64,
+    #[getset(get_copy = "pub(crate)")]
+    attempts: u32,
+    #[getset(get = "pub(crate)")]
+    log: Option<PathBuf>,
+    /// The first error cargo reported for a failing job
+    #[getset(get = "pub(crate)")]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    error: Option<Diagnostic>,
+}
+
+impl Entry {
+    /// A job that never ran, i.e. one of a plan that no report holds
+    pub(crate) fn not_run(
+        id: String,
+        package: String,
+        channel: String,
+        features: FeatureSet,
+        command: String,
+    ) -> Self {
+        Self {
+            id,
+            package,
+            channel,
+            features,
+            command,
+            outcome: Outcome::NotRun,
+            exit_code: None,
+            duration: 0.0,
+            attempts: 0,
+            log: None,
+            error: None,
+        }
+    }
+
+    /// The result the entry was made from
+    pub(crate) fn result(&self) -> TaskResult {
+        let code = self.exit_code.unwrap_or(-1);
+        match self.outcome {
+            Outcome::Pass => TaskResult::Success,
+            Outcome::Flaky => TaskResult::Flaky,
+            Outcome::Fail => TaskResult::Fail(code),
+            Outcome::CompileError => TaskResult::CompileError(code),
+            Outcome::Timeout => TaskResult::Timeout,
+            Outcome::Interrupted => TaskResult::Interrupted,
+            Outcome::NotRun => TaskResult::NotRun,
+            Outcome::Resumed => TaskResult::Resumed,
+            Outcome::Cached => TaskResult::Cached,
+        }
+    }
+}
+
+#[cfg(test)]
+impl Entry {
+    /// An entry for a job of `foo` on the default channel that took a second
+    pub(crate) fn of(features: &[&str], result: TaskResult, attempts: u32) -> Self {
+        let features: FeatureSet = features.iter().copied().map(Into::into).collect();
+        Self {
+            id: features.to_string(),
+            package: "foo".to_string(),
+            channel: "default".to_string(),
+            command: format!("cargo check -p foo -F {features}"),
+            features,
+            outcome: Outcome::from(result),
+            exit_code: result.code(),
+            duration: 1.0,
+            attempts,
+            log: None,
+            error: None,
+        }
+    }
+}
+
+impl From<&JobReport> for Entry {
+    fn from(report: &JobReport) -> Self {
+        let result = *report.result();
+        Self {
+            id: report.job().planned_id(),
+            package: report.job().package().clone(),
+            channel: report.job().channel().clone(),
+            features: report.job().feature_set().clone(),
+            command: report.job().command_line(),
+            outcome: Outcome::from(result),
+            exit_code: result.code(),
+            duration: report.duration().as_secs_f64(),
+            attempts: *report.attempts(),
+            log: report.log().clone(),
+            error: report.error().clone(),
+        }
+    }
+}
+
+/// How a job ended, the serialized form of a `TaskResult`
+#[derive(Clone, Copy, Debug, Deserialize, Eq, PartialEq, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub(crate) enum Outcome {
+    Pass,
+    Flaky,
+    Fail,
+    CompileError,
+    Timeout,
+    Interrupted,
+    NotRun,
+    Resumed,
+    Cached,
+}
+
+impl From<TaskResult> for Outcome {
+    fn from(result: TaskResult) -> Self {
+        match result {
+            TaskResult::Success => Outcome::Pass,
+            TaskResult::Flaky => Outcome::Flaky,
+            TaskResult::Fail(_) => Outcome::Fail,
+            TaskResult::CompileError(_) => Outcome::CompileError,
+            TaskResult::Timeout => Outcome::Timeout,
+            TaskResult::Interrupted => Outcome::Interrupted,
+            TaskResult::NotRun => Outcome::NotRun,
+            TaskResult::Resumed => Outcome::Resumed,
+            TaskResult::Cached => Outcome::Cached,
+        }
+    }
+}
+
+/// Group the entries by package and channel, keeping the order they first appear in
+fn by_package(report: &Report) -> Vec<((&str, &str), Vec<&Entry>)> {
+    let mut groups: Vec<((&str, &str), Vec<&Entry>)> = Vec::new();
+    for entry in report.jobs() {
+        let key = (entry.package().as_str(), entry.channel().as_str());
+        match groups.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, entries)) => entries.push(entry),
+            None => groups.push((key, vec![entry])),
+        }
+    }
+    groups
+}
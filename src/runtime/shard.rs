@@ -0,0 +1,287 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::FeatureSet,
+    runtime::{execute::Job, report::Report},
+};
+use anyhow::Result;
+use getset::CopyGetters;
+use sha2::{Digest, Sha256};
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    fmt::{Display, Formatter},
+    path::Path,
+    str::FromStr,
+};
+
+/// How far above an even share of the weight a shard may go before jobs spill over to
+/// their next choice of shard
+const SLACK: f64 = 0.25;
+
+/// A `<index>/<count>` shard of the jobs from the command line, indexed at 1
+#[derive(Clone, Copy, CopyGetters, Debug, Eq, PartialEq)]
+#[getset(get_copy = "pub(crate)")]
+pub(crate) struct Shard {
+    index: usize,
+    count: usize,
+}
+
+impl FromStr for Shard {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (index, count) = s
+            .split_once('/')
+            .ok_or_else(|| format!("expected <index>/<count>, got '{s}'"))?;
+        let index: usize = index
+            .parse()
+            .map_err(|_| format!("invalid shard index '{index}'"))?;
+        let count: usize = count
+            .parse()
+            .map_err(|_| format!("invalid shard count '{count}'"))?;
+        if count == 0 {
+            return Err("the shard count cannot be 0".to_string());
+        }
+        if index == 0 || index > count {
+            return Err(format!("the shard index must be between 1 and {count}"));
+        }
+        Ok(Self { index, count })
+    }
+}
+
+impl Display for Shard {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.index, self.count)
+    }
+}
+
+impl Shard {
+    /// Keep only the jobs assigned to this shard
+    pub(crate) fn select(self, jobs: Vec<Job>, weights: &Weights) -> Vec<Job> {
+        let assignment = assign(&jobs, self.count, weights);
+        jobs.into_iter()
+            .zip(assignment)
+            .filter(|(_, shard)| *shard == self.index - 1)
+            .map(|(job, _)| job)
+            .collect()
+    }
+}
+
+/// What a job costs when balancing the shards
+#[derive(Clone, Debug)]
+pub(crate) enum Weights {
+    /// Every job costs the same
+    Count,
+    /// Jobs cost what they took in an earlier run, by package, channel and feature set
+    Durations(HashMap<(String, String, FeatureSet), f64>),
+}
+
+impl Weights {
+    /// The durations of the jobs that ran to completion in an earlier JSON report
+    pub(crate) fn from_report(path: &Path) -> Result<Self> {
+        let report = Report::read(path)?;
+        let durations = report
+            .jobs()
+            .iter()
+            .filter(|entry| entry.result().is_pass() || entry.result().is_failure())
+            .filter(|entry| entry.duration() > 0.0)
+            .map(|entry| {
+                (
+                    (
+                        entry.package().clone(),
+                        entry.channel().clone(),
+                        entry.features().clone(),
+                    ),
+                    entry.duration(),
+                )
+            })
+            .collect();
+        Ok(Weights::Durations(durations))
+    }
+
+    fn of(&self, jobs: &[Job]) -> Vec<f64> {
+        match self {
+            Weights::Count => vec![1.0; jobs.len()],
+            Weights::Durations(durations) => {
+                let known: Vec<Option<f64>> = jobs
+                    .iter()
+                    .map(|job| {
+                        durations
+                            .get(&(
+                                job.package().clone(),
+                                job.channel().clone(),
+                                job.feature_set().clone(),
+                            ))
+                            .copied()
+                    })
+                    .collect();
+                // Jobs that are new since the earlier run are guessed to take the average
+                let found: Vec<f64> = known.iter().flatten().copied().collect();
+                let average = if found.is_empty() {
+                    1.0
+                } else {
+                    found.iter().sum::<f64>() / found.len() as f64
+                };
+                known
+                    .into_iter()
+                    .map(|weight| weight.unwrap_or(average))
+                    .collect()
+            }
+        }
+    }
+}
+
+/// Consistent hashing with bounded loads. Every job ranks the shards by a hash of its id
+/// and goes to the first one with room left, so adding or removing jobs only moves the
+/// few jobs that spill over, while no shard ends up with much more than its share.
+///
+/// The room is `1 + SLACK` times an even share, or the heaviest job if that is more. A
+/// job that fits nowhere goes to the least loaded shard, which is below an even share
+/// without it. With every job costing the same, no shard gets more than
+/// `⌊(1 + SLACK) · jobs / shards⌋` jobs, or `⌈jobs / shards⌉` if that is more.
+fn assign(jobs: &[Job], count: usize, weights: &Weights) -> Vec<usize> {
+    let ids: Vec<String> = jobs.iter().map(Job::id).collect();
+    let weights = weights.of(jobs);
+    let total: f64 = weights.iter().sum();
+    let heaviest = weights.iter().copied().fold(0.0, f64::max);
+    let capacity = (total / count as f64 * (1.0 + SLACK)).max(heaviest);
+
+    // Placing jobs in the order of their ids keeps the order they were planned in out of it
+    let mut order: Vec<usize> = (0..jobs.len()).collect();
+    order.sort_by(|a, b| ids[*a].cmp(&ids[*b]));
+
+    let mut loads = vec![0.0; count];
+    let mut assignment = vec![0; jobs.len()];
+    for job in order {
+        let mut ranked: Vec<usize> = (0..count).collect();
+        ranked.sort_by_key(|shard| Reverse(score(&ids[job], *shard)));
+        let shard = ranked
+            .iter()
+            .copied()
+            .find(|shard| loads[*shard] + weights[job] <= capacity)
+            .unwrap_or_else(|| {
+                (0..count)
+                    .min_by(|a, b| loads[*a].total_cmp(&loads[*b]))
+                    .unwrap_or_default()
+            });
+        loads[shard] += weights[job];
+        assignment[job] = shard;
+    }
+    assignment
+}
+
+/// How much the job with this id prefers the shard
+fn score(id: &str, shard: usize) -> u64 {
+    let digest = Sha256::digest(format!("{id}/{shard}").as_bytes());
+    let mut bytes = [0; 8];
+    bytes.copy_from_slice(&digest[..8]);
+    u64::from_be_bytes(bytes)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{assign, Shard, Weights, SLACK};
+    use crate::runtime::execute::Job;
+
+    /// `count` jobs, each checking a different single feature
+    fn jobs(count: usize) -> Vec<Job> {
+        (0..count)
+            .map(|feature| Job::check("foo", &[&format!("f{feature:02}")]))
+            .collect()
+    }
+
+    /// How many jobs each of `count` shards gets
+    fn loads(assignment: &[usize], count: usize) -> Vec<usize> {
+        let mut loads = vec![0; count];
+        for shard in assignment {
+            loads[*shard] += 1;
+        }
+        loads
+    }
+
+    #[test]
+    fn parses_shards() {
+        let shard: Shard = "2/4".parse().unwrap();
+        assert_eq!((shard.index(), shard.count()), (2, 4));
+        assert_eq!(shard.to_string(), "2/4");
+        assert_eq!("1/1".parse::<Shard>().unwrap().to_string(), "1/1");
+        for invalid in ["2", "a/4", "1/b", "1/0", "0/4", "5/4", "-1/4", "1/2/3"] {
+            assert!(invalid.parse::<Shard>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn shards_hold_their_share() {
+        for total in 1..=60_usize {
+            for count in 1..=6 {
+                let most = ((1.0 + SLACK) * total as f64 / count as f64).floor() as usize;
+                let most = most.max(total.div_ceil(count));
+                let loads = loads(&assign(&jobs(total), count, &Weights::Count), count);
+                assert_eq!(loads.iter().sum::<usize>(), total);
+                assert!(
+                    loads.iter().all(|load| *load <= most),
+                    "{loads:?} for {total} jobs, at most {most} each"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn shards_hold_their_share_of_the_durations() {
+        let jobs = jobs(40);
+        let durations = jobs
+            .iter()
+            .enumerate()
+            .map(|(index, job)| {
+                (
+                    (
+                        job.package().clone(),
+                        job.channel().clone(),
+                        job.feature_set().clone(),
+                    ),
+                    (index % 7 + 1) as f64,
+                )
+            })
+            .collect();
+        let weights = Weights::Durations(durations);
+        let durations = weights.of(&jobs);
+        let total: f64 = durations.iter().sum();
+        let heaviest = durations.iter().copied().fold(0.0, f64::max);
+        for count in 2..=5 {
+            let mut loads = vec![0.0; count];
+            for (job, shard) in assign(&jobs, count, &weights).into_iter().enumerate() {
+                loads[shard] += durations[job];
+            }
+            // A job that fits nowhere takes a shard past its room, by at most its weight
+            let most = total / count as f64 * (1.0 + SLACK) + heaviest;
+            assert!(loads.iter().all(|load| *load <= most), "{loads:?}");
+        }
+    }
+
+    #[test]
+    fn adding_a_job_moves_few_others() {
+        for total in 20..60 {
+            for count in 2..=5 {
+                let before = assign(&jobs(total), count, &Weights::Count);
+                let after = assign(&jobs(total + 1), count, &Weights::Count);
+                // The new job sorts last, so the others keep their index
+                let moved = before
+                    .iter()
+                    .zip(&after)
+                    .filter(|(before, after)| before != after)
+                    .count();
+                assert!(
+                    moved <= total / 5,
+                    "{moved} of {total} jobs moved between {count} shards"
+                );
+            }
+        }
+    }
+}
@@ -0,0 +1,158 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::{
+    feature::FeatureSet,
+    runtime::execute::{Job, JobReport, TaskResult},
+};
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fs::{File, OpenOptions},
+    io::{BufRead, BufReader, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+/// One completed job, stored as a line of JSON
+#[derive(Debug, Deserialize, Serialize)]
+struct Entry {
+    package: String,
+    channel: String,
+    features: FeatureSet,
+    command: String,
+    result: String,
+    passed: bool,
+}
+
+/// Records the result of each job as soon as it completes, so an interrupted run can
+/// be resumed without redoing the jobs that already passed
+#[derive(Debug)]
+pub(crate) struct StateFile {
+    file: Mutex<File>,
+    passed: HashSet<(String, String)>,
+}
+
+impl StateFile {
+    /// Open the state file, keeping the previous results if resuming and starting
+    /// over otherwise
+    pub(crate) fn open(path: &Path, resume: bool) -> Result<Self> {
+        let mut passed = HashSet::new();
+        if resume && path.exists() {
+            let reader = BufReader::new(File::open(path)?);
+            for (number, line) in reader.lines().enumerate() {
+                let line = line?;
+                if line.trim().is_empty() {
+                    continue;
+                }
+                let entry: Entry = serde_json::from_str(&line).with_context(|| {
+                    format!("invalid entry on line {} of {}", number + 1, path.display())
+                })?;
+                // Later entries win, a job may have failed before it passed or vice versa
+                let key = (entry.channel, entry.command);
+                if entry.passed {
+                    let _ = passed.insert(key);
+                } else {
+                    let _ = passed.remove(&key);
+                }
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .append(resume)
+            .truncate(!resume)
+            .open(path)?;
+        Ok(Self {
+            file: Mutex::new(file),
+            passed,
+        })
+    }
+
+    /// Did this job pass in a previous run
+    pub(crate) fn passed(&self, job: &Job) -> bool {
+        self.passed
+            .contains(&(job.channel().clone(), job.command_line()))
+    }
+
+    /// Append the result of a completed job. Jobs that never finished are not recorded.
+    pub(crate) fn record(&self, report: &JobReport) -> Result<()> {
+        let passed = match report.result() {
+            TaskResult::Success | TaskResult::Flaky => true,
+            TaskResult::Fail(_) | TaskResult::CompileError(_) | TaskResult::Timeout => false,
+            _ => return Ok(()),
+        };
+        let job = report.job();
+        let entry = Entry {
+            package: job.package().clone(),
+            channel: job.channel().clone(),
+            features: job.feature_set().clone(),
+            command: job.command_line(),
+            result: report.result().label().to_lowercase(),
+            passed,
+        };
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+        let mut file = self
+            .file
+            .lock()
+            .map_err(|_| anyhow!("state file lock poisoned"))?;
+        file.write_all(line.as_bytes())?;
+        file.flush()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StateFile;
+    use crate::runtime::execute::{Job, JobReport, TaskResult};
+    use std::{env, fs};
+
+    #[test]
+    fn resumes_from_the_last_result_of_each_job() {
+        let name = format!("cargo-matrix-state-{}.jsonl", std::process::id());
+        let path = env::temp_dir().join(name);
+        let (a, b, c) = (
+            Job::check("foo", &["a"]),
+            Job::check("foo", &["b"]),
+            Job::check("foo", &["c"]),
+        );
+        let record = |state: &StateFile, job: &Job, result: TaskResult| {
+            state.record(&JobReport::ran(job, result, None)).unwrap();
+        };
+
+        let state = StateFile::open(&path, false).unwrap();
+        record(&state, &a, TaskResult::Success);
+        record(&state, &b, TaskResult::Fail(101));
+        // Jobs that never finished leave no trace
+        record(&state, &c, TaskResult::Interrupted);
+        let state = StateFile::open(&path, true).unwrap();
+        assert!(state.passed(&a));
+        assert!(!state.passed(&b));
+        assert!(!state.passed(&c));
+
+        // Later entries win, a failure after a pass clears it
+        record(&state, &a, TaskResult::Timeout);
+        record(&state, &b, TaskResult::Flaky);
+        let state = StateFile::open(&path, true).unwrap();
+        assert!(!state.passed(&a));
+        assert!(state.passed(&b));
+        assert_eq!(fs::read_to_string(&path).unwrap().lines().count(), 4);
+
+        // Without resuming, the run starts over
+        let state = StateFile::open(&path, false).unwrap();
+        assert!(!state.passed(&b));
+        assert!(fs::read_to_string(&path).unwrap().is_empty());
+        drop(state);
+        assert!(!StateFile::open(&path, true).unwrap().passed(&b));
+        let _ = fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,231 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::runtime::{
+    diagnostic,
+    execute::{JobReport, TaskResult},
+    report::Culprit,
+};
+use anyhow::Result;
+use std::io::Write;
+use yansi::Paint;
+
+const HEADERS: [&str; 6] = [
+    "package", "channel", "features", "result", "duration", "exit",
+];
+
+/// Write a table of every job that ran, followed by the commands that reproduce each failure,
+/// the distinct errors they failed with and what the failures have in common
+pub(crate) fn write(
+    out: &mut dyn Write,
+    reports: &[JobReport],
+    culprits: &[Culprit],
+) -> Result<()> {
+    let rows: Vec<[String; 6]> = reports.iter().map(row).collect();
+    let mut widths = HEADERS.map(str::len);
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.len());
+        }
+    }
+
+    writeln!(out, "{}", Paint::cyan("     Summary").bold())?;
+    writeln!(out)?;
+    let header = HEADERS
+        .iter()
+        .zip(widths)
+        .map(|(header, width)| format!("{header:<width$}"))
+        .collect::<Vec<_>>()
+        .join("  ");
+    writeln!(out, "{}", Paint::new(header.trim_end()).bold())?;
+    for (row, report) in rows.iter().zip(reports) {
+        let cells: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .enumerate()
+            .map(|(column, (cell, width))| {
+                let cell = format!("{cell:<width$}");
+                if column == 3 {
+                    cell.as_str().fg(report.result().color()).to_string()
+                } else {
+                    cell
+                }
+            })
+            .collect();
+        writeln!(out, "{}", cells.join("  ").trim_end())?;
+    }
+    writeln!(out)?;
+
+    let failures: Vec<&JobReport> = reports.iter().filter(|r| is_failure(r)).collect();
+    if !failures.is_empty() {
+        writeln!(out, "{}", Paint::cyan("   Reproduce").bold())?;
+        writeln!(out)?;
+        for report in &failures {
+            writeln!(out, "{}", report.job().command_line())?;
+        }
+        writeln!(out)?;
+    }
+
+    // Grouping only helps once cargo told us about at least one error
+    let groups = diagnostic::group(reports);
+    if groups.iter().any(|group| group.error.is_some()) {
+        writeln!(out, "{}", Paint::cyan("      Errors").bold())?;
+        writeln!(out)?;
+        for group in &groups {
+            match group.error {
+                Some(error) => writeln!(out, "{}: {error}", group.package)?,
+                None => writeln!(out, "{}: failed without a compiler error", group.package)?,
+            }
+            let sets: Vec<String> = group
+                .reports
+                .iter()
+                .map(|report| format!("[{}]", report.job().feature_set()))
+                .collect();
+            writeln!(out, "  {} set(s): {}", sets.len(), sets.join(" "))?;
+            if let Some(report) = group.reproducer() {
+                writeln!(out, "  {}", report.job().command_line())?;
+            }
+        }
+        writeln!(out)?;
+    }
+
+    if !culprits.is_empty() {
+        writeln!(out, "{}", Paint::cyan("    Culprits").bold())?;
+        writeln!(out)?;
+        for culprit in culprits {
+            writeln!(
+                out,
+                "{} ({}): {}, {} set(s)",
+                culprit.package(),
+                culprit.channel(),
+                culprit.message(),
+                culprit.failed()
+            )?;
+        }
+        writeln!(out)?;
+    }
+
+    let count = |f: fn(&TaskResult) -> bool| reports.iter().filter(|r| f(r.result())).count();
+    let passed = count(|result| result.is_pass());
+    let flaky = count(|result| matches!(result, TaskResult::Flaky));
+    let interrupted = count(|result| matches!(result, TaskResult::Interrupted));
+    let not_run = count(|result| matches!(result, TaskResult::NotRun));
+    let compile_errors = count(|result| matches!(result, TaskResult::CompileError(_)));
+    write!(
+        out,
+        "{} {} job(s), {passed} passed ({flaky} flaky), {} failed",
+        Paint::cyan("      Totals").bold(),
+        reports.len(),
+        failures.len()
+    )?;
+    if compile_errors > 0 {
+        write!(out, " ({compile_errors} to compile)")?;
+    }
+    if interrupted > 0 {
+        write!(out, ", {interrupted} interrupted")?;
+    }
+    if not_run > 0 {
+        write!(out, ", {not_run} not run")?;
+    }
+    writeln!(out)?;
+    writeln!(out)?;
+    Ok(())
+}
+
+fn is_failure(report: &JobReport) -> bool {
+    report.result().is_failure()
+}
+
+fn row(report: &JobReport) -> [String; 6] {
+    let result = report.result().label().to_string();
+    let exit = report
+        .result()
+        .code()
+        .map_or_else(|| "-".to_string(), |code| code.to_string());
+    let features = report.job().feature_set().to_string();
+    [
+        report.job().package().clone(),
+        report.job().channel().clone(),
+        if features.is_empty() {
+            "(none)".to_string()
+        } else {
+            features
+        },
+        result,
+        format!("{:.1}s", report.duration().as_secs_f64()),
+        exit,
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use super::write;
+    use crate::runtime::{
+        execute::{Job, JobReport, TaskResult},
+        report::Report,
+    };
+
+    /// The text without its colors
+    fn plain(text: &str) -> String {
+        let mut plain = String::new();
+        let mut chars = text.chars();
+        while let Some(c) = chars.next() {
+            if c == '\x1b' {
+                let _ = chars.by_ref().find(|c| *c == 'm');
+            } else {
+                plain.push(c);
+            }
+        }
+        plain
+    }
+
+    #[test]
+    fn summarizes_the_failures() {
+        let reports: Vec<JobReport> = [
+            (&[][..], TaskResult::Success),
+            (&["a"][..], TaskResult::Flaky),
+            (&["b"][..], TaskResult::Fail(101)),
+            (&["a", "b"][..], TaskResult::Fail(101)),
+        ]
+        .into_iter()
+        .map(|(features, result)| JobReport::ran(&Job::check("foo", features), result, None))
+        .collect();
+        let report = Report::new(&reports);
+
+        let mut out = Vec::new();
+        write(&mut out, &reports, report.culprits()).unwrap();
+        let command = |index: usize| reports[index].job().command_line();
+        assert_eq!(
+            plain(&String::from_utf8(out).unwrap()),
+            format!(
+                "     Summary
+
+package  channel  features  result  duration  exit
+foo      default  (none)    OK      0.0s      0
+foo      default  a         FLAKY   0.0s      0
+foo      default  b         FAILED  0.0s      101
+foo      default  a,b       FAILED  0.0s      101
+
+   Reproduce
+
+{}
+{}
+
+    Culprits
+
+foo (default): every set containing `b` fails, 2 set(s)
+
+      Totals 4 job(s), 2 passed (1 flaky), 2 failed
+
+",
+                command(2),
+                command(3)
+            )
+        );
+    }
+}
@@ -0,0 +1,112 @@
+// Copyright (c) 2024 cargo-matrix developers
+//
+// Licensed under the Apache License, Version 2.0
+// <LICENSE-APACHE or https://www.apache.org/licenses/LICENSE-2.0> or the MIT
+// license <LICENSE-MIT or https://opensource.org/licenses/MIT>, at your
+// option. All files in the project carrying such notice may not be copied,
+// modified, or distributed except according to those terms.
+
+use crate::feature::FeatureSet;
+use std::path::Path;
+
+/// Fill in the placeholders of an argument template for one feature set.
+///
+/// * `{package}` is the package name
+/// * `{features}` is the comma separated feature set, i.e. `a,b`
+/// * `{features_spaced}` is the space separated feature set, i.e. `a b`. On its own it
+///   becomes one argument per feature.
+/// * `{features_flag}` is `--features=a,b`
+/// * `{manifest}` is the path to the manifest
+///
+/// Arguments that end up empty, i.e. a lone `{features}` for the empty set, are dropped.
+pub(crate) fn expand(
+    template: &[String],
+    package: &str,
+    feature_set: &FeatureSet,
+    manifest: Option<&Path>,
+) -> Vec<String> {
+    let features = feature_set.to_string();
+    let spaced = feature_set
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>();
+    let flag = if feature_set.is_empty() {
+        String::new()
+    } else {
+        format!("--features={features}")
+    };
+    let manifest = manifest
+        .map(|manifest| format!("{}", manifest.display()))
+        .unwrap_or_default();
+
+    template
+        .iter()
+        .flat_map(|arg| {
+            if arg == "{features_spaced}" {
+                return spaced.clone();
+            }
+            vec![arg
+                .replace("{package}", package)
+                .replace("{features_spaced}", &spaced.join(" "))
+                .replace("{features_flag}", &flag)
+                .replace("{features}", &features)
+                .replace("{manifest}", &manifest)]
+        })
+        .filter(|arg| !arg.is_empty())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::expand;
+    use crate::feature::FeatureSet;
+    use std::path::Path;
+
+    fn template(args: &[&str]) -> Vec<String> {
+        args.iter().map(ToString::to_string).collect()
+    }
+
+    #[test]
+    fn fills_in_placeholders() {
+        let feature_set: FeatureSet = ["a", "b"].into_iter().map(Into::into).collect();
+        let args = expand(
+            &template(&[
+                "{package}",
+                "{features}",
+                "{features_spaced}",
+                "{features_flag}",
+                "--manifest-path={manifest}",
+            ]),
+            "foo",
+            &feature_set,
+            Some(Path::new("foo/Cargo.toml")),
+        );
+        assert_eq!(
+            args,
+            [
+                "foo",
+                "a,b",
+                "a",
+                "b",
+                "--features=a,b",
+                "--manifest-path=foo/Cargo.toml"
+            ]
+        );
+    }
+
+    #[test]
+    fn drops_empty_arguments() {
+        let args = expand(
+            &template(&[
+                "build",
+                "{features}",
+                "{features_spaced}",
+                "{features_flag}",
+            ]),
+            "foo",
+            &FeatureSet::default(),
+            None,
+        );
+        assert_eq!(args, ["build"]);
+    }
+}